@@ -0,0 +1,475 @@
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::{
+    fmt,
+    iter::Peekable,
+    str::Chars,
+};
+
+use crate::vm::*;
+
+/// Why `assemble` rejected its source, and on which line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub kind: AsmErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    UnknownOp(String),
+    /// An op was missing an operand it needs.
+    MissingOperand,
+    BadOperand(String),
+    UnknownLabel(String),
+    DuplicateLabel(String),
+    UnclosedString,
+    BadChar,
+    /// A `fun`, `rawfun`, or `closure` with no matching `end`.
+    UnclosedFun,
+    /// An `end` with nothing to close.
+    UnexpectedEnd,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            AsmErrorKind::UnknownOp(op)    => write!(f, "unknown op `{}`", op),
+            AsmErrorKind::MissingOperand  => write!(f, "missing operand"),
+            AsmErrorKind::BadOperand(o)   => write!(f, "bad operand `{}`", o),
+            AsmErrorKind::UnknownLabel(l) => write!(f, "unknown label `{}`", l),
+            AsmErrorKind::DuplicateLabel(l) => {
+                write!(f, "label `{}` is defined twice", l)
+            },
+            AsmErrorKind::UnclosedString  => write!(f, "unclosed string"),
+            AsmErrorKind::BadChar         => write!(f, "bad char literal"),
+            AsmErrorKind::UnclosedFun     => write!(f, "`fun` without `end`"),
+            AsmErrorKind::UnexpectedEnd   => write!(f, "`end` without `fun`"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for AsmError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Char(char),
+}
+
+/// Assembles a `Fun` from text.
+///
+/// Each op is written as its name in snake case,
+/// followed by its operands, separated by whitespace:
+/// `const 3.0`, `raise_n 0 2`, `handler 0`.
+/// A line may hold any number of ops, and `;` starts a comment.
+///
+/// `const` takes an `Int` like `3`, a `Float` like `3.0`,
+/// `true`, `false`, `unit`, a `"string"`, or a `'c'`har.
+/// Numbers assemble to `ConstI` and `ConstF`, the rest to `Const`.
+/// Function constants are written inline, between `fun` and `end`,
+/// or `rawfun n` and `end` for a `RawFun` with `n` captures.
+/// `closure n` and `end` is written the same way as `rawfun`.
+///
+/// `name:` marks a label, which `jump` and `jump_if_false`
+/// can use in place of an op index. Labels are local to a function.
+///
+/// Effects can be named by a symbol, as in `raise state`,
+/// instead of a number. Symbols are numbered in order of appearance,
+/// so mixing the two may make different effects share a `Name`.
+/// Use `assemble_program` to keep the symbols.
+pub fn assemble(src: &str) -> Result<Fun, AsmError> {
+    Ok(assemble_program(src)?.entry)
+}
+
+/// Like `assemble`, but returns a `Program`
+/// with the symbols given to effects.
+pub fn assemble_program(src: &str) -> Result<Program, AsmError> {
+    assemble_with(src, Symbols::new())
+}
+
+/// Like `assemble_program`, but carries on from `symbols`,
+/// so symbols it already has keep their `Name`s
+/// and new ones are numbered after them.
+pub fn assemble_with(src: &str, mut symbols: Symbols) -> Result<Program, AsmError> {
+    let mut tokens = tokenize(src)?.into_iter().peekable();
+    let entry = assemble_block(&mut tokens, &mut symbols, None)?;
+
+    let mut program = Program::new(entry);
+    program.symbols = symbols;
+    Ok(program)
+}
+
+fn tokenize(src: &str) -> Result<Vec<(usize, Token)>, AsmError> {
+    let mut tokens = vec![];
+
+    for (index, text) in src.lines().enumerate() {
+        let line = index + 1;
+        let error = |kind| AsmError { line, kind };
+        let mut chars = text.chars().peekable();
+
+        while let Some(&c) = chars.peek() {
+            match c {
+                ';' => break,
+                c if c.is_whitespace() => { chars.next(); },
+                '"' => {
+                    chars.next();
+                    let string = read_quoted(&mut chars, '"')
+                        .ok_or_else(|| error(AsmErrorKind::UnclosedString))?;
+                    tokens.push((line, Token::Str(string)));
+                },
+                '\'' => {
+                    chars.next();
+                    let string = read_quoted(&mut chars, '\'')
+                        .ok_or_else(|| error(AsmErrorKind::BadChar))?;
+                    let mut string = string.chars();
+                    match (string.next(), string.next()) {
+                        (Some(c), None) => tokens.push((line, Token::Char(c))),
+                        _ => return Err(error(AsmErrorKind::BadChar)),
+                    }
+                },
+                _ => {
+                    let mut word = String::new();
+                    while let Some(&c) = chars.peek() {
+                        if c.is_whitespace() || c == ';' { break; }
+                        word.push(c);
+                        chars.next();
+                    }
+                    tokens.push((line, Token::Word(word)));
+                },
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Reads up to the closing `quote`, handling `\n`, `\t`, and `\` escapes.
+fn read_quoted(chars: &mut Peekable<Chars>, quote: char) -> Option<String> {
+    let mut string = String::new();
+    loop {
+        match chars.next()? {
+            c if c == quote => return Some(string),
+            '\\' => match chars.next()? {
+                'n' => string.push('\n'),
+                't' => string.push('\t'),
+                c   => string.push(c),
+            },
+            c => string.push(c),
+        }
+    }
+}
+
+type Tokens = Peekable<alloc::vec::IntoIter<(usize, Token)>>;
+
+/// Assembles ops up to a matching `end`, or the end of the source
+/// when `opened` is `None`; otherwise `opened` is the line it began on.
+fn assemble_block(
+    tokens: &mut Tokens,
+    symbols: &mut Symbols,
+    opened: Option<usize>,
+) -> Result<Fun, AsmError> {
+    let mut builder = FunBuilder::new();
+    let mut labels: BTreeMap<String, (Label, bool)> = BTreeMap::new();
+    let mut first_use: BTreeMap<String, usize> = BTreeMap::new();
+
+    loop {
+        let (line, token) = match tokens.next() {
+            Some(next) => next,
+            None => match opened {
+                Some(line) => {
+                    return Err(AsmError { line, kind: AsmErrorKind::UnclosedFun });
+                },
+                None => break,
+            },
+        };
+        let error = |kind| AsmError { line, kind };
+
+        let word = match token {
+            Token::Word(word) => word,
+            Token::Str(s) => return Err(error(AsmErrorKind::UnknownOp(s))),
+            Token::Char(c) => {
+                return Err(error(AsmErrorKind::UnknownOp(c.to_string())));
+            },
+        };
+
+        if let Some(name) = word.strip_suffix(':') {
+            let label = labels.entry(name.to_string())
+                .or_insert_with(|| (builder.label(), false));
+            if label.1 {
+                return Err(error(AsmErrorKind::DuplicateLabel(name.to_string())));
+            }
+            label.1 = true;
+            builder.mark(label.0);
+            continue;
+        }
+
+        let op = match word.as_str() {
+            "end" => match opened {
+                Some(_) => break,
+                None => return Err(error(AsmErrorKind::UnexpectedEnd)),
+            },
+            "fun" => {
+                let fun = assemble_block(tokens, symbols, Some(line))?;
+                Op::Const(Data::Fun(fun))
+            },
+            "rawfun" | "closure" => {
+                let num_captures = operand(tokens, line)?;
+                let fun = assemble_block(tokens, symbols, Some(line))?;
+                let raw_fun = RawFun { ops: fun.ops, num_captures, spans: None };
+                if word == "closure" {
+                    Op::Closure(raw_fun)
+                } else {
+                    Op::Const(Data::RawFun(raw_fun))
+                }
+            },
+
+            "jump" | "jump_if_false" => {
+                let target = match tokens.next() {
+                    Some((_, Token::Word(target))) => target,
+                    Some((_, other)) => {
+                        let other = format!("{:?}", other);
+                        return Err(error(AsmErrorKind::BadOperand(other)));
+                    },
+                    None => return Err(error(AsmErrorKind::MissingOperand)),
+                };
+
+                match target.parse() {
+                    Ok(index) if word == "jump" => Op::Jump(index),
+                    Ok(index) => Op::JumpIfFalse(index),
+                    Err(_) => {
+                        first_use.entry(target.clone()).or_insert(line);
+                        let label = labels.entry(target)
+                            .or_insert_with(|| (builder.label(), false)).0;
+                        if word == "jump" {
+                            builder.jump_to(label);
+                        } else {
+                            builder.jump_if_false_to(label);
+                        }
+                        continue;
+                    },
+                }
+            },
+
+            "const" => {
+                let data = match tokens.next() {
+                    Some((_, Token::Str(s)))  => Data::Str(s.into()),
+                    Some((_, Token::Char(c))) => Data::Char(c),
+                    Some((_, Token::Word(w))) => literal(&w)
+                        .ok_or_else(|| error(AsmErrorKind::BadOperand(w)))?,
+                    None => return Err(error(AsmErrorKind::MissingOperand)),
+                };
+                Op::constant(data)
+            },
+
+            "return"          => Op::Return(operand(tokens, line)?),
+            "const_idx"       => Op::ConstIdx(operand(tokens, line)?),
+            "get"             => Op::Get(Name(operand(tokens, line)?)),
+            "set"             => Op::Set(Name(operand(tokens, line)?)),
+            "handler" => Op::Handler(effect(tokens, symbols, line)?),
+            "shallow_handler" => {
+                Op::ShallowHandler(effect(tokens, symbols, line)?)
+            },
+            "unhandle" => Op::Unhandle(effect(tokens, symbols, line)?),
+            "raise"   => Op::Raise(effect(tokens, symbols, line)?),
+            "try_raise" => Op::TryRaise(effect(tokens, symbols, line)?),
+            "raise_n" => Op::RaiseN(
+                effect(tokens, symbols, line)?,
+                operand(tokens, line)?,
+            ),
+            "pop"             => Op::Pop(operand(tokens, line)?),
+            "rot"             => Op::Rot(operand(tokens, line)?),
+            "pack"            => Op::Pack(operand(tokens, line)?),
+            "call_n"          => Op::CallN(operand(tokens, line)?),
+
+            "call"          => Op::Call,
+            "halt"          => Op::Halt,
+            "assert"        => Op::Assert,
+            "assert_msg"    => Op::AssertMsg,
+            "yield"         => Op::Yield,
+            "tail_call"     => Op::TailCall,
+            "add"           => Op::Add,
+            "sub"           => Op::Sub,
+            "mul"           => Op::Mul,
+            "div"           => Op::Div,
+            "mod"           => Op::Mod,
+            "neg"           => Op::Neg,
+            "eq"            => Op::Eq,
+            "lt"            => Op::Lt,
+            "gt"            => Op::Gt,
+            "and"           => Op::And,
+            "or"            => Op::Or,
+            "not"           => Op::Not,
+            "to_bool"       => Op::ToBool,
+            "print"         => Op::Print,
+            "int_to_float"  => Op::IntToFloat,
+            "float_to_int"  => Op::FloatToInt,
+            "is_nan"        => Op::IsNaN,
+            "is_inf"        => Op::IsInf,
+            "handle_return" => Op::HandleReturn,
+            "drop"          => Op::Drop,
+            "dup"           => Op::Dup,
+            "swap"          => Op::Swap,
+            "over"          => Op::Over,
+            "nip"           => Op::Nip,
+            "unpack"        => Op::Unpack,
+            "concat"        => Op::Concat,
+            "str_repeat"    => Op::StrRepeat,
+            "str_len"       => Op::StrLen,
+            "str_index"     => Op::StrIndex,
+            "char_to_int"   => Op::CharToInt,
+            "int_to_char"   => Op::IntToChar,
+            "bytes_len"     => Op::BytesLen,
+            "bytes_get"     => Op::BytesGet,
+            "str_to_bytes"  => Op::StrToBytes,
+            "bytes_to_str"  => Op::BytesToStr,
+            "list_new"      => Op::ListNew,
+            "list_push"     => Op::ListPush,
+            "list_get"      => Op::ListGet,
+            "list_len"      => Op::ListLen,
+            "map_new"       => Op::MapNew,
+            "map_set"       => Op::MapSet,
+            "map_get"       => Op::MapGet,
+            "capture"       => Op::Capture,
+            "resume"        => Op::Resume,
+            "clone_cont"    => Op::CloneCont,
+
+            _ => return Err(error(AsmErrorKind::UnknownOp(word))),
+        };
+        builder.push(op);
+    }
+
+    for (name, line) in first_use {
+        if !labels[&name].1 {
+            return Err(AsmError { line, kind: AsmErrorKind::UnknownLabel(name) });
+        }
+    }
+
+    Ok(builder.build())
+}
+
+/// Parses the next token as a numeric operand.
+fn operand(tokens: &mut Tokens, line: usize) -> Result<usize, AsmError> {
+    match tokens.next() {
+        Some((_, Token::Word(word))) => word.parse()
+            .map_err(|_| AsmError { line, kind: AsmErrorKind::BadOperand(word) }),
+        Some((_, other)) => Err(AsmError {
+            line,
+            kind: AsmErrorKind::BadOperand(format!("{:?}", other)),
+        }),
+        None => Err(AsmError { line, kind: AsmErrorKind::MissingOperand }),
+    }
+}
+
+/// Parses the next token as the `Name` of an effect,
+/// either a number or a symbol.
+fn effect(
+    tokens: &mut Tokens,
+    symbols: &mut Symbols,
+    line: usize,
+) -> Result<Name, AsmError> {
+    match tokens.next() {
+        Some((_, Token::Word(word))) => match word.parse() {
+            Ok(index) => Ok(Name(index)),
+            Err(_) => Ok(symbols.intern(&word)),
+        },
+        Some((_, other)) => Err(AsmError {
+            line,
+            kind: AsmErrorKind::BadOperand(format!("{:?}", other)),
+        }),
+        None => Err(AsmError { line, kind: AsmErrorKind::MissingOperand }),
+    }
+}
+
+/// Parses a `const` operand that isn't a `Str` or `Char`.
+fn literal(word: &str) -> Option<Data> {
+    match word {
+        "unit"  => Some(Data::Unit),
+        "true"  => Some(Data::Bool(true)),
+        "false" => Some(Data::Bool(false)),
+        _ => match word.parse::<i64>() {
+            Ok(n) => Some(Data::Int(n)),
+            Err(_) => word.parse::<f64>().ok().map(Data::Float),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assembles_the_demo() {
+        let fun = assemble("
+            const 3.0 const 4.0 const 5.0
+            add div
+            fun call end
+            handler 0
+            raise 0
+        ").unwrap();
+        assert!(matches!(
+            &fun.ops[..],
+            [
+                Op::ConstF(_), Op::ConstF(_), Op::ConstF(_),
+                Op::Add, Op::Div,
+                Op::Const(Data::Fun(_)),
+                Op::Handler(Name(0)),
+                Op::Raise(Name(0)),
+            ]
+        ));
+        assert_eq!(Fiber::new(fun).run().unwrap(), Data::Float(3.0 / 9.0));
+    }
+
+    #[test]
+    fn resolves_labels_in_a_loop() {
+        let fun = assemble("
+            const 0 set 0
+            top: get 0 const 3 lt jump_if_false done
+                get 0 const 1 add set 0
+                jump top
+            done: get 0
+        ").unwrap();
+        assert!(matches!(fun.ops[5], Op::JumpIfFalse(11)));
+        assert!(matches!(fun.ops[10], Op::Jump(2)));
+        assert_eq!(Fiber::new(fun).run().unwrap(), Data::Int(3));
+    }
+
+    #[test]
+    fn reports_the_line_of_an_error() {
+        let error = assemble("const 1\nconst 2\nfrobnicate").unwrap_err();
+        assert_eq!(error, AsmError { line: 3, kind: AsmErrorKind::UnknownOp("frobnicate".into()) });
+        let error = assemble("const 1\njump nowhere").unwrap_err();
+        assert_eq!(error.line, 2);
+        assert_eq!(error.kind, AsmErrorKind::UnknownLabel("nowhere".into()));
+        let error = assemble("top: top:").unwrap_err();
+        assert_eq!(error.kind, AsmErrorKind::DuplicateLabel("top".into()));
+    }
+
+    #[test]
+    fn names_effects_by_symbol() {
+        let program = assemble_program("handler state raise io raise state").unwrap();
+        assert!(matches!(
+            program.entry.ops[..],
+            [Op::Handler(Name(0)), Op::Raise(Name(1)), Op::Raise(Name(0))]
+        ));
+        let fiber = program.fiber();
+        assert_eq!(fiber.name_of(Name(0)), Some("state"));
+        assert_eq!(fiber.name_of(Name(1)), Some("io"));
+        assert_eq!(fiber.name_of(Name(2)), None);
+    }
+
+    #[test]
+    fn unhandled_named_effect_reports_its_symbol() {
+        let error = assemble_program("const 1 raise log").unwrap().fiber().run().unwrap_err();
+        assert_eq!(format!("{}", error), "unhandled effect log (0) with arguments (1)");
+        assert!(format!("{:?}", error).contains("\"log\""));
+    }
+}
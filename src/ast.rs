@@ -0,0 +1,175 @@
+use alloc::{
+    boxed::Box,
+    string::String,
+    vec,
+    vec::Vec,
+};
+use core::fmt;
+
+use crate::vm::*;
+
+/// An expression in a small language that compiles to bytecode.
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Lit(Data),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
+    Div(Box<Expr>, Box<Expr>),
+    /// Evaluates the second expression if the first is `true`,
+    /// and the third otherwise.
+    If(Box<Expr>, Box<Expr>, Box<Expr>),
+    /// Binds a name to the value of the first expression
+    /// while evaluating the second. Inner bindings shadow outer ones.
+    Let(String, Box<Expr>, Box<Expr>),
+    Var(String),
+    /// Raises an effect with the value of the expression as its argument,
+    /// evaluating to whatever the handler resumes with.
+    Raise(Name, Box<Expr>),
+}
+
+/// Why `compile` rejected an expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileError {
+    /// A `Var` with no `Let` binding it.
+    UnboundVariable(String),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::UnboundVariable(name) => {
+                write!(f, "unbound variable `{}`", name)
+            },
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for CompileError {}
+
+/// Compiles `expr` to a function that returns its value.
+/// Each `Let` gets a slot of its own, numbered from 0.
+pub fn compile(expr: &Expr) -> Result<Fun, CompileError> {
+    let mut compiler = Compiler {
+        builder: FunBuilder::new(),
+        scope:   vec![],
+        slots:   0,
+    };
+    compiler.expr(expr)?;
+    compiler.builder.push(Op::Return(1));
+    Ok(compiler.builder.build())
+}
+
+struct Compiler {
+    builder: FunBuilder,
+    /// The names in scope and their slots, innermost last.
+    scope:   Vec<(String, usize)>,
+    /// How many slots have been given out.
+    slots:   usize,
+}
+
+impl Compiler {
+    /// Emits ops that push the value of `expr`.
+    fn expr(&mut self, expr: &Expr) -> Result<(), CompileError> {
+        match expr {
+            Expr::Lit(data) => {
+                self.builder.push(Op::constant(data.clone()));
+            },
+            Expr::Add(a, b) => self.binary(a, b, Op::Add)?,
+            Expr::Sub(a, b) => self.binary(a, b, Op::Sub)?,
+            Expr::Mul(a, b) => self.binary(a, b, Op::Mul)?,
+            Expr::Div(a, b) => self.binary(a, b, Op::Div)?,
+            Expr::If(cond, then, otherwise) => {
+                let (other, end) = (self.builder.label(), self.builder.label());
+                self.expr(cond)?;
+                self.builder.jump_if_false_to(other);
+                self.expr(then)?;
+                self.builder.jump_to(end).mark(other);
+                self.expr(otherwise)?;
+                self.builder.mark(end);
+            },
+            Expr::Let(name, value, body) => {
+                let slot = self.slots;
+                self.slots += 1;
+                self.expr(value)?;
+                self.builder.push(Op::Set(Name(slot)));
+
+                self.scope.push((name.clone(), slot));
+                self.expr(body)?;
+                self.scope.pop();
+            },
+            Expr::Var(name) => {
+                let slot = self.scope.iter().rev()
+                    .find(|(bound, _)| bound == name)
+                    .map(|(_, slot)| *slot)
+                    .ok_or_else(|| CompileError::UnboundVariable(name.clone()))?;
+                self.builder.push(Op::Get(Name(slot)));
+            },
+            Expr::Raise(effect, arg) => {
+                self.expr(arg)?;
+                self.builder.push(Op::Raise(*effect));
+            },
+        }
+        Ok(())
+    }
+
+    /// Emits `a`, then `b`, then `op` to combine them.
+    fn binary(&mut self, a: &Expr, b: &Expr, op: Op) -> Result<(), CompileError> {
+        self.expr(a)?;
+        self.expr(b)?;
+        self.builder.push(op);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(n: i64) -> Box<Expr> { Box::new(Expr::Lit(Data::Int(n))) }
+    fn var(name: &str) -> Box<Expr> { Box::new(Expr::Var(name.into())) }
+
+    fn run(expr: Expr) -> Result<Data, Effect> {
+        Fiber::new(compile(&expr).unwrap()).run()
+    }
+
+    #[test]
+    fn let_binds_a_variable() {
+        // let x = 2 in x + x * 3
+        let body = Expr::Add(var("x"), Box::new(Expr::Mul(var("x"), int(3))));
+        let expr = Expr::Let("x".into(), int(2), Box::new(body));
+        assert_eq!(run(expr).unwrap(), Data::Int(8));
+    }
+
+    #[test]
+    fn if_picks_a_branch() {
+        let branch = |cond| Expr::If(Box::new(Expr::Lit(Data::Bool(cond))), int(1), int(2));
+        assert_eq!(run(branch(true)).unwrap(), Data::Int(1));
+        assert_eq!(run(branch(false)).unwrap(), Data::Int(2));
+    }
+
+    #[test]
+    fn inner_let_shadows_only_its_body() {
+        // let x = 1 in (let x = 2 in x) + x
+        let inner = Expr::Let("x".into(), int(2), var("x"));
+        let expr = Expr::Let("x".into(), int(1), Box::new(Expr::Add(Box::new(inner), var("x"))));
+        assert_eq!(run(expr).unwrap(), Data::Int(3));
+    }
+
+    #[test]
+    fn raise_evaluates_to_what_the_handler_gives() {
+        let expr = Expr::Sub(Box::new(Expr::Raise(Name(0), int(4))), int(1));
+        let mut fiber = Fiber::new(compile(&expr).unwrap()).with_host(Name(0), |data| match data {
+            Data::Int(n) => Ok(Data::Int(n * 10)),
+            other => Err(Effect::TypeMismatch { expected: "Int", found: other.type_name() }),
+        });
+        assert_eq!(fiber.run().unwrap(), Data::Int(39));
+    }
+
+    #[test]
+    fn unbound_variable_is_an_error() {
+        let expr = Expr::Let("x".into(), int(1), var("y"));
+        assert_eq!(compile(&expr).unwrap_err(), CompileError::UnboundVariable("y".into()));
+    }
+}
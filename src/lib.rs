@@ -0,0 +1,9 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod vm;
+pub mod asm;
+pub mod ast;
+#[cfg(feature = "std")]
+pub mod repl;
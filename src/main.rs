@@ -1,11 +1,15 @@
 use std::rc::Rc;
 
-pub mod vm;
-
-use vm::*;
+use effective::{repl, vm::*};
 
 fn main() {
-    use Op::*;
+    if std::env::args().nth(1).as_deref() == Some("repl") {
+        if let Err(error) = repl::repl() {
+            eprintln!("{}", error);
+        }
+        return;
+    }
+
     let ops = vec![
         Op::Const(Data::Float(3.0)),
         Op::Const(Data::Float(4.0)),
@@ -17,6 +21,7 @@ fn main() {
                 Op::Call,
             ]),
             captures: Rc::new(vec![]),
+            spans: None,
         })),
         Op::Handler(Name(0)),
         Op::Raise(Name(0)),
@@ -25,9 +30,10 @@ fn main() {
     let fun = Fun {
         ops: Rc::new(ops),
         captures: Rc::new(vec![]),
+        spans: None,
     };
 
-    let mut fiber = Fiber::new(fun);
+    let mut fiber = Fiber::new(fun).with_trace(true);
     println!("Result: {:#?}", fiber.run());
 
     println!("Fiber: {:#?}", fiber);
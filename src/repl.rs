@@ -0,0 +1,199 @@
+use std::{
+    fmt,
+    io::{self, BufRead, Write},
+    rc::Rc,
+};
+
+use crate::{asm::*, vm::*};
+
+/// Why `eval_line` failed: the line didn't assemble,
+/// or running it raised an effect.
+#[derive(Debug)]
+pub enum ReplError {
+    Asm(AsmError),
+    Effect(Effect),
+}
+
+impl fmt::Display for ReplError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplError::Asm(error)    => write!(f, "{}", error),
+            ReplError::Effect(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for ReplError {}
+
+impl From<AsmError> for ReplError {
+    fn from(error: AsmError) -> ReplError {
+        ReplError::Asm(error)
+    }
+}
+
+impl From<Effect> for ReplError {
+    fn from(error: Effect) -> ReplError {
+        ReplError::Effect(error)
+    }
+}
+
+/// What a REPL keeps between lines: a fiber,
+/// whose values, locals, and handlers carry over,
+/// and the symbols given to effects so far.
+#[derive(Debug)]
+pub struct ReplState {
+    pub fiber: Fiber,
+    symbols:   Symbols,
+}
+
+impl ReplState {
+    pub fn new() -> ReplState {
+        let empty = Fun {
+            ops:      Rc::new(vec![]),
+            captures: Rc::new(vec![]),
+            spans:    None,
+        };
+        ReplState { fiber: Fiber::new(empty), symbols: Symbols::new() }
+    }
+}
+
+impl Default for ReplState {
+    fn default() -> ReplState {
+        ReplState::new()
+    }
+}
+
+/// Assembles `src` and runs it on top of what earlier lines left,
+/// returning the value then on top of the stack, or `Unit` if it's empty.
+/// The value stays on the stack for the next line to use.
+/// If handlers resumed the line, they get its value once it ends,
+/// and what they return is the line's value instead.
+/// A line that doesn't reach its end, as a handler didn't resume it,
+/// or that fails, is undone, so the state is as it was before it.
+pub fn eval_line(state: &mut ReplState, src: &str) -> Result<Data, ReplError> {
+    let program = assemble_with(src, state.symbols.clone())?;
+    state.symbols = program.symbols;
+
+    // the line stops at a yield once it is done, with its values
+    // and its base frame's locals and handlers still there
+    let mut ops = (*program.entry.ops).clone();
+    ops.push(Op::Yield);
+    let ops = Rc::new(ops);
+
+    let before = state.fiber.snapshot();
+    state.fiber.load(Fun {
+        ops:      ops.clone(),
+        captures: Rc::new(vec![]),
+        spans:    None,
+    });
+    match run_line(&mut state.fiber, &ops) {
+        Ok((data, true)) => Ok(data),
+        Ok((data, false)) => {
+            state.fiber = before;
+            Ok(data)
+        },
+        Err(error) => {
+            state.fiber = before;
+            Err(error.into())
+        },
+    }
+}
+
+/// Runs the line of `ops` on `fiber`, returning its value,
+/// and whether it reached its end.
+fn run_line(fiber: &mut Fiber, ops: &Rc<Vec<Op>>) -> Result<(Data, bool), Effect> {
+    loop {
+        match fiber.step()? {
+            Status::Yielded if Rc::ptr_eq(fiber.ops(), ops) && fiber.pc() == ops.len() => {
+                let value = fiber.stack_data().last().cloned().unwrap_or(Data::Unit);
+                return Ok((fiber.hand_off(value)?, true));
+            },
+            Status::Done(result) => return Ok((result, false)),
+            _ => (),
+        }
+    }
+}
+
+/// Reads lines from stdin and evaluates them in turn,
+/// printing each result or error, until stdin is closed.
+pub fn repl() -> io::Result<()> {
+    let mut state = ReplState::new();
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+
+    loop {
+        write!(stdout, "> ")?;
+        stdout.flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return writeln!(stdout);
+        }
+
+        match eval_line(&mut state, &line) {
+            Ok(data)   => writeln!(stdout, "{}", data)?,
+            Err(error) => writeln!(stdout, "error: {}", error)?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_carry_over_between_lines() {
+        let mut state = ReplState::new();
+        assert_eq!(eval_line(&mut state, "const 2.0").unwrap(), Data::Float(2.0));
+        assert_eq!(eval_line(&mut state, "const 3.0 add").unwrap(), Data::Float(5.0));
+    }
+
+    #[test]
+    fn locals_carry_over() {
+        let mut state = ReplState::new();
+        eval_line(&mut state, "const 4 set 0").unwrap();
+        assert_eq!(eval_line(&mut state, "get 0 get 0 mul").unwrap(), Data::Int(16));
+    }
+
+    #[test]
+    fn handlers_and_symbols_carry_over() {
+        let mut state = ReplState::new();
+        let error = eval_line(&mut state, "const unit raise ask").unwrap_err();
+        assert!(matches!(error, ReplError::Effect(Effect::Virtual { name: Name(0), .. })));
+
+        let mut state = ReplState::new();
+        eval_line(&mut state, "fun drop const 10 resume return 1 end handler ask").unwrap();
+        assert_eq!(eval_line(&mut state, "const unit raise ask").unwrap(), Data::Int(10));
+        assert_eq!(state.symbols.name_of(Name(0)), Some("ask"));
+    }
+
+    #[test]
+    fn locals_and_handlers_survive_a_handled_raise() {
+        let mut state = ReplState::new();
+        eval_line(&mut state, "const 7 set 0").unwrap();
+        eval_line(&mut state, "fun drop drop const 1 return 1 end handler ask").unwrap();
+        assert_eq!(eval_line(&mut state, "const unit raise ask").unwrap(), Data::Int(1));
+        assert_eq!(eval_line(&mut state, "get 0").unwrap(), Data::Int(7));
+        assert_eq!(eval_line(&mut state, "const unit raise ask").unwrap(), Data::Int(1));
+
+        // a handler that resumes keeps what the line did
+        let mut state = ReplState::new();
+        eval_line(&mut state, "fun drop const 10 resume const 1 add return 1 end handler ask")
+            .unwrap();
+        let line = "const 7 set 0 const unit raise ask";
+        assert_eq!(eval_line(&mut state, line).unwrap(), Data::Int(11));
+        assert_eq!(eval_line(&mut state, "get 0").unwrap(), Data::Int(7));
+        assert_eq!(eval_line(&mut state, "const unit raise ask").unwrap(), Data::Int(11));
+    }
+
+    #[test]
+    fn errors_leave_the_state_usable() {
+        let mut state = ReplState::new();
+        eval_line(&mut state, "const 1").unwrap();
+        assert!(matches!(eval_line(&mut state, "frobnicate"), Err(ReplError::Asm(_))));
+        let error = eval_line(&mut state, "const true add").unwrap_err();
+        assert!(matches!(error, ReplError::Effect(Effect::TypeMismatch { .. })));
+        assert_eq!(state.fiber.stack_data(), [Data::Int(1)]);
+        assert_eq!(eval_line(&mut state, "const 2").unwrap(), Data::Int(2));
+    }
+}
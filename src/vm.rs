@@ -1,61 +1,742 @@
-use std::{
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    format,
     rc::Rc,
-    collections::BTreeMap,
+    string::String,
+    vec,
+    vec::Vec,
+};
+use core::{
+    cell::{Cell, RefCell},
+    cmp::Ordering,
+    convert::TryFrom,
+    mem,
+    fmt,
+};
+#[cfg(feature = "std")]
+use std::{
+    collections::HashMap,
+    io,
+    mem::Discriminant,
 };
 
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Name(pub usize);
 
+/// The source names of the `Name`s a program uses,
+/// so they can be shown to people.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Symbols {
+    names: Vec<Rc<str>>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    /// Returns the `Name` for `symbol`, giving it the next one
+    /// if it doesn't have one yet.
+    pub fn intern(&mut self, symbol: &str) -> Name {
+        match self.names.iter().position(|name| &**name == symbol) {
+            Some(index) => Name(index),
+            None => {
+                self.names.push(symbol.into());
+                Name(self.names.len() - 1)
+            },
+        }
+    }
+
+    pub fn name_of(&self, Name(index): Name) -> Option<&str> {
+        self.names.get(index).map(|name| &**name)
+    }
+}
+
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Op {
+    /// Returns the top `n` values to the caller;
+    /// `Return(0)` returns `Unit`, as every call produces a value.
+    /// The rest of the values the function pushed are discarded,
+    /// and returning more than it pushed is `Fatal`.
     Return(usize),
+    /// Ends the fiber at once with the top value as its result,
+    /// however deep in calls it is, without running return clauses,
+    /// and without handing the result to a fiber that resumed it.
+    Halt,
+    /// Pauses the fiber, with `step` returning `Status::Yielded`,
+    /// and `run` `Effect::Yielded`, so the host can do other work;
+    /// stepping again, or `resume`, carries on after the `Yield`.
+    /// Unlike an effect, it has no name or handler.
+    Yield,
+    /// Pops an argument, then a `Fun` or `Native`, and calls it.
+    /// Calling a `Cont` continues it in place of the caller:
+    /// the argument becomes the result of the suspended `Raise`,
+    /// but unlike `Resume` nothing comes back to the caller,
+    /// whose remaining ops are dropped; the continuation's result
+    /// goes to whatever the caller would have handed its own to.
     Call,
+    /// Pops `n` arguments, then a `Fun` or `Native`, and calls it.
+    /// A `Fun` starts with the arguments on its stack in order,
+    /// the first deepest, where it can `Set` them to slots;
+    /// they are discarded with the rest of its values on return.
+    /// A `Native` is passed one argument as is,
+    /// and a `Tuple` of them otherwise.
+    CallN(usize),
+    /// Pushes the constant at the given index in the program's
+    /// constant pool; unlike `Const`, the value is stored only once.
+    ConstIdx(usize),
+    /// Pops an argument, then a `Fun`, and calls it in place
+    /// of the current frame, as `Call` followed by `Return(1)` would,
+    /// but without growing the frame stack.
+    /// The values and locals of the current frame are discarded;
+    /// its handlers and return clause carry over to the callee,
+    /// which returns straight to the current frame's caller.
+    TailCall,
     Const(Data),
+    /// Pushes a `Float`; cheaper than `Const` for a bare number.
+    ConstF(f64),
+    /// Pushes an `Int`; cheaper than `Const` for a bare number.
+    ConstI(i64),
     Add,
+    Sub,
+    Mul,
     Div,
+    Mod,
+    Neg,
+    /// Compares any two values, following `PartialEq for Data`.
+    Eq,
+    Lt,
+    Gt,
+    /// Pops two `Bool`s and pushes whether both are true.
+    /// Both operands are already evaluated; this doesn't short-circuit.
+    And,
+    /// Pops two `Bool`s and pushes whether either is true.
+    Or,
+    Not,
+    /// Pops a `Bool`, raising `AssertionFailed` with the `pc`
+    /// when it is `false`.
+    Assert,
+    /// Pops a `Str` message, then a `Bool`, raising `AssertionFailed`
+    /// with the `pc` and the message when it is `false`.
+    AssertMsg,
+    /// Pops any value and pushes whether it is truthy,
+    /// following `Data::is_truthy`.
+    ToBool,
+    /// Pops a value and writes it to the fiber's output, on its own line.
+    Print,
+    IntToFloat,
+    /// Truncates toward zero, saturating at the bounds of `Int`.
+    FloatToInt,
+    /// Pops a `Float` and pushes whether it is `NaN`.
+    /// Arithmetic on `Float`s follows IEEE 754, so `NaN` and the
+    /// infinities propagate; only dividing by zero raises an effect.
+    IsNaN,
+    /// Pops a `Float` and pushes whether it is infinite, either way.
+    IsInf,
+    /// Continues execution at the given op index;
+    /// jumping to the end of the ops finishes the function.
+    Jump(usize),
+    /// Pops a `Bool`, jumping only if it is `false`.
+    JumpIfFalse(usize),
     Get(Name),
     Set(Name),
+    /// Pops a `Fun` and installs it as a deep handler for an effect
+    /// in the current frame: it stays installed after handling it,
+    /// so it catches every raise of that effect in the frame.
+    /// Installing a second handler for the same effect
+    /// in the same frame raises `DuplicateHandler` instead.
     Handler(Name),
+    /// Like `Handler`, but the handler is removed as soon as it fires,
+    /// and must be reinstalled to catch the effect again.
+    ShallowHandler(Name),
+    /// Removes the handler for an effect from the current frame,
+    /// so a handler can cover just part of a function.
+    /// Does nothing if the frame has none, as a shallow handler
+    /// may already have removed itself by firing.
+    Unhandle(Name),
+    /// Pops a `Fun` and installs it as the return clause
+    /// of the current frame, replacing any previous one.
+    HandleReturn,
     Raise(Name),
+    /// Like `Raise`, but if neither a handler nor a host callback
+    /// handles the effect, the argument is left on the stack
+    /// as the result instead of the effect escaping to the host.
+    TryRaise(Name),
+    /// Raises an effect with the top `n` values as its arguments.
+    RaiseN(Name, usize),
+    /// Discards the top `n` values, or fails without popping any
+    /// if there are fewer.
     Pop(usize),
-    // Resume,
+    /// Same as `Pop(1)`.
+    Drop,
+    Dup,
+    Swap,
+    /// Pushes a copy of the value below the top, so `a b` becomes `a b a`.
+    Over,
+    /// Discards the value below the top, so `a b` becomes `b`.
+    Nip,
+    /// Rotates the top `n` values, moving the top value down
+    /// to the `n`th from the top and the others up by one,
+    /// so `a b c` becomes `c a b` with `Rot(3)`.
+    /// `Rot(2)` is the same as `Swap`.
+    Rot(usize),
+    /// Pops `n` values into a `Tuple`, the first pushed first.
+    Pack(usize),
+    /// Pushes the elements of a `Tuple` in order.
+    Unpack,
+    Concat,
+    /// Pops an `Int` count and a `Str`, pushing the `Str` repeated
+    /// that many times. A negative count raises `NegativeCount`,
+    /// and a result longer than the fiber's `max_str_len`
+    /// in bytes raises `StrTooLong`.
+    StrRepeat,
+    /// Pushes the length of a `Str` in chars.
+    StrLen,
+    /// Pops an `Int` index and a `Str`, pushing the `Char` at that index.
+    /// Like `StrLen`, this counts chars, not bytes.
+    StrIndex,
+    /// Pushes the Unicode scalar value of a `Char`.
+    CharToInt,
+    /// Pops an `Int` and pushes the `Char` with that scalar value.
+    IntToChar,
+    /// Pushes the length of a `Bytes` in bytes.
+    BytesLen,
+    /// Pops an `Int` index and a `Bytes`,
+    /// pushing the byte at that index as an `Int`.
+    BytesGet,
+    /// Pushes the UTF-8 encoding of a `Str` as `Bytes`.
+    StrToBytes,
+    /// Pushes the `Str` a `Bytes` encodes as UTF-8.
+    BytesToStr,
+    ListNew,
+    /// Pops a value and a list, appends the value,
+    /// then pushes the list back.
+    ListPush,
+    /// Pops an `Int` index and a list, pushing the element at that index.
+    ListGet,
+    ListLen,
+    MapNew,
+    /// Pops a value, a key, and a map, sets the key to the value,
+    /// then pushes the map back.
+    MapSet,
+    /// Pops a key and a map, pushing the value of that key.
+    MapGet,
+    Capture,
+    /// Pops the captures of the `RawFun` and pushes it as a `Fun`,
+    /// like pushing it and then running `Capture`.
+    Closure(RawFun),
+    /// Pops a value, then the continuation below it,
+    /// and resumes the continuation with that value
+    /// as the result of the `Raise` that suspended it.
+    /// Once the continuation finishes, the resuming fiber
+    /// carries on after the `Resume` with its result on top.
+    Resume,
+    /// Like `Dup`, but the copy of the continuation on top of the stack
+    /// is independent, so both can be resumed.
+    CloneCont,
+}
+
+impl Op {
+    /// The op that pushes `data`, using `ConstF` or `ConstI`
+    /// for a bare number and `Const` otherwise.
+    pub fn constant(data: Data) -> Op {
+        match data {
+            Data::Float(n) => Op::ConstF(n),
+            Data::Int(n)   => Op::ConstI(n),
+            other => Op::Const(other),
+        }
+    }
+}
+
+/// Renders `ops` as text, one instruction per line,
+/// with the bodies of function constants indented below them.
+pub fn disassemble(ops: &[Op]) -> String {
+    let mut out = String::new();
+    disassemble_into(&mut out, ops, 0);
+    out
+}
+
+fn disassemble_into(out: &mut String, ops: &[Op], indent: usize) {
+    for (pc, op) in ops.iter().enumerate() {
+        let body = match op {
+            Op::Const(Data::Fun(fun)) => Some((
+                "Const",
+                format!(
+                    "<fun> captures {} ops {}",
+                    fun.captures.len(), fun.ops.len(),
+                ),
+                &fun.ops,
+            )),
+            Op::Const(Data::RawFun(raw)) => Some((
+                "Const",
+                format!(
+                    "<rawfun> captures {} ops {}",
+                    raw.num_captures, raw.ops.len(),
+                ),
+                &raw.ops,
+            )),
+            Op::Closure(raw) => Some((
+                "Closure",
+                format!("captures {} ops {}", raw.num_captures, raw.ops.len()),
+                &raw.ops,
+            )),
+            _ => None,
+        };
+
+        // the mnemonic is the variant name, the operands are the rest
+        let debug = format!("{:?}", op);
+        let (mnemonic, operands) = match &body {
+            Some((mnemonic, header, _)) => (*mnemonic, header.as_str()),
+            None => match debug.find('(') {
+                Some(i) => (&debug[..i], &debug[i + 1..debug.len() - 1]),
+                None => (debug.as_str(), ""),
+            },
+        };
+
+        let line = format!("{:04}  {:<9} {}", pc, mnemonic, operands);
+        out.push_str(&"    ".repeat(indent));
+        out.push_str(line.trim_end());
+        out.push('\n');
+
+        if let Some((_, _, ops)) = body {
+            disassemble_into(out, ops, indent + 1);
+        }
+    }
+}
+
+/// Why `verify` rejected a function, naming the `pc` of the offending op.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    /// A jump to past the end of the ops.
+    JumpOutOfRange { pc: usize, target: usize },
+    /// A `Get` of a slot that is neither a capture
+    /// nor `Set` anywhere in the function.
+    UnboundSlot { pc: usize, slot: usize },
+    /// A `Set` of a slot past the last a frame can have.
+    SlotOutOfRange { pc: usize, slot: usize },
+    /// An op would pop more values than the function has pushed.
+    StackUnderflow { pc: usize },
+    /// Two paths reach the same op with different stack heights.
+    StackMismatch { pc: usize },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::JumpOutOfRange { pc, target } => {
+                write!(f, "jump at pc {} to out of range target {}", pc, target)
+            },
+            VerifyError::UnboundSlot { pc, slot } => {
+                write!(f, "get at pc {} of unbound slot {}", pc, slot)
+            },
+            VerifyError::SlotOutOfRange { pc, slot } => {
+                write!(f, "set at pc {} of out of range slot {}", pc, slot)
+            },
+            VerifyError::StackUnderflow { pc } => {
+                write!(f, "stack underflow at pc {}", pc)
+            },
+            VerifyError::StackMismatch { pc } => {
+                write!(f, "paths reach pc {} with different stack heights", pc)
+            },
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for VerifyError {}
+
+/// What `verify` knows about the stack before an op.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Abstract {
+    /// How many values the function has pushed,
+    /// or `None` after an op that pushes an unknown number, like `Unpack`.
+    height: Option<usize>,
+    /// The number of captures of the `RawFun` on top of the stack,
+    /// if it is known to be one.
+    raw_top: Option<usize>,
+}
+
+/// Checks `fun`, as run on an empty stack, is well formed.
+/// See `verify_with_args`.
+pub fn verify(fun: &Fun) -> Result<(), VerifyError> {
+    verify_with_args(fun, 0)
+}
+
+/// Checks that `fun`, when started with `args` values on its stack,
+/// only jumps to ops within it, only `Get`s slots that are bound
+/// and `Set`s slots a frame can have,
+/// and never pops more values than it has, along any path.
+///
+/// Calls are assumed to leave a single value, like `Return(1)`.
+/// Function constants aren't checked, as the number of arguments
+/// they start with depends on how they are called;
+/// verify them separately.
+pub fn verify_with_args(fun: &Fun, args: usize) -> Result<(), VerifyError> {
+    use Op::*;
+
+    let ops = &fun.ops;
+    let set: Vec<usize> = ops.iter()
+        .filter_map(|op| match op { Set(Name(i)) => Some(*i), _ => None })
+        .collect();
+
+    let mut states: Vec<Option<Abstract>> = vec![None; ops.len() + 1];
+    let mut work = vec![0];
+    states[0] = Some(Abstract { height: Some(args), raw_top: None });
+
+    while let Some(pc) = work.pop() {
+        if pc == ops.len() { continue; }
+        let state = states[pc].unwrap();
+
+        let jump_target = |target: usize| if target > ops.len() {
+            Err(VerifyError::JumpOutOfRange { pc, target })
+        } else {
+            Ok(target)
+        };
+
+        // how many values the op pops and pushes,
+        // `None` pushes meaning an unknown number.
+        let (pops, pushes, next) = match &ops[pc] {
+            Return(n) => (*n, Some(0), vec![]),
+            Halt => (1, Some(0), vec![]),
+            Yield => (0, Some(0), vec![pc + 1]),
+            Call => (2, Some(1), vec![pc + 1]),
+            CallN(n) => (*n + 1, Some(1), vec![pc + 1]),
+            TailCall => (2, Some(0), vec![]),
+            Resume => (2, Some(1), vec![pc + 1]),
+            Const(_) | ConstF(_) | ConstI(_) | ConstIdx(_)
+            | ListNew | MapNew => {
+                (0, Some(1), vec![pc + 1])
+            },
+            Add | Sub | Mul | Div | Mod | Eq | Lt | Gt | And | Or
+            | Concat | StrRepeat | StrIndex | BytesGet
+            | ListPush | ListGet | MapGet => {
+                (2, Some(1), vec![pc + 1])
+            },
+            Neg | Not | ToBool | IntToFloat | FloatToInt | IsNaN | IsInf
+            | StrLen | CharToInt | IntToChar | BytesLen | StrToBytes | BytesToStr
+            | ListLen => {
+                (1, Some(1), vec![pc + 1])
+            },
+            Jump(target) => (0, Some(0), vec![jump_target(*target)?]),
+            JumpIfFalse(target) => {
+                (1, Some(0), vec![pc + 1, jump_target(*target)?])
+            },
+            Unhandle(_) => (0, Some(0), vec![pc + 1]),
+            Get(Name(slot)) => {
+                if *slot >= fun.captures.len() && !set.contains(slot) {
+                    return Err(VerifyError::UnboundSlot { pc, slot: *slot });
+                }
+                (0, Some(1), vec![pc + 1])
+            },
+            Set(Name(slot)) if *slot >= Frame::MAX_SLOTS => {
+                return Err(VerifyError::SlotOutOfRange { pc, slot: *slot });
+            },
+            Set(_) | Handler(_) | ShallowHandler(_) | HandleReturn
+            | Drop | Print | Assert => {
+                (1, Some(0), vec![pc + 1])
+            },
+            Raise(_) | TryRaise(_) => (1, Some(1), vec![pc + 1]),
+            RaiseN(_, n) => (*n, Some(1), vec![pc + 1]),
+            Pop(n) => (*n, Some(0), vec![pc + 1]),
+            Dup | CloneCont => (1, Some(2), vec![pc + 1]),
+            Swap => (2, Some(2), vec![pc + 1]),
+            Over => (2, Some(3), vec![pc + 1]),
+            Nip => (2, Some(1), vec![pc + 1]),
+            Rot(n) => (*n, Some(*n), vec![pc + 1]),
+            Pack(n) => (*n, Some(1), vec![pc + 1]),
+            MapSet => (3, Some(1), vec![pc + 1]),
+            AssertMsg => (2, Some(0), vec![pc + 1]),
+            Unpack => (1, None, vec![pc + 1]),
+            Closure(raw) => (raw.num_captures, Some(1), vec![pc + 1]),
+            Capture => match state.raw_top {
+                Some(captures) => (1 + captures, Some(1), vec![pc + 1]),
+                None => (1, None, vec![pc + 1]),
+            },
+        };
+
+        let height = match state.height {
+            Some(height) if height < pops => {
+                return Err(VerifyError::StackUnderflow { pc });
+            },
+            Some(height) => pushes.map(|pushes| height - pops + pushes),
+            None => None,
+        };
+        let raw_top = match &ops[pc] {
+            Const(Data::RawFun(raw)) => Some(raw.num_captures),
+            _ => None,
+        };
+        let after = Abstract { height, raw_top };
+
+        // states only ever lose precision when merged, so this ends
+        for next in next {
+            let merged = match states[next] {
+                None => after,
+                Some(seen) => {
+                    let height = match (seen.height, after.height) {
+                        (Some(a), Some(b)) if a != b => {
+                            return Err(VerifyError::StackMismatch { pc: next });
+                        },
+                        (a, b) => a.and(b),
+                    };
+                    let raw_top = if seen.raw_top == after.raw_top {
+                        seen.raw_top
+                    } else {
+                        None
+                    };
+                    Abstract { height, raw_top }
+                },
+            };
+
+            if states[next] != Some(merged) {
+                states[next] = Some(merged);
+                work.push(next);
+            }
+        }
+    }
+
+    Ok(())
 }
 
+/// Represents a function before its captures are bound;
+/// `Capture` turns it into a `Fun`.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RawFun {
-    ops: Rc<Vec<Op>>,
-    num_captures: usize,
+    pub ops:          Rc<Vec<Op>>,
+    pub num_captures: usize,
+    pub spans:        Option<Rc<Vec<Span>>>,
 }
 
 /// Represents a function before execution
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Fun {
     pub ops:      Rc<Vec<Op>>,
     pub captures: Rc<Vec<Data>>,
+    /// Where each op came from in the source, if known;
+    /// parallel to `ops`.
+    pub spans:    Option<Rc<Vec<Span>>>,
 }
 
-#[derive(Debug)]
+impl Fun {
+    /// Whether two functions share the same ops and have equal captures.
+    /// This is about identity rather than behaviour: the same body
+    /// compiled twice isn't the same, even though it does the same thing.
+    /// `Eq` compares functions this way.
+    pub fn same(&self, other: &Fun) -> bool {
+        Rc::ptr_eq(&self.ops, &other.ops)
+        && (Rc::ptr_eq(&self.captures, &other.captures)
+            || self.captures == other.captures)
+    }
+
+    /// Renders the function as a tree: a header with its number
+    /// of captures and ops, then its ops as `disassemble` renders them,
+    /// with the function constants among them nested the same way.
+    pub fn pretty(&self) -> String {
+        let mut out = format!(
+            "fun captures {} ops {}\n",
+            self.captures.len(),
+            self.ops.len(),
+        );
+        disassemble_into(&mut out, &self.ops, 1);
+        out
+    }
+}
+
+/// A compiled program, ready to be run or saved.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Program {
+    pub entry:     Fun,
+    /// The constant pool, indexed by `ConstIdx`.
+    pub constants: Vec<Data>,
+    pub symbols:   Symbols,
+}
+
+impl Program {
+    pub fn new(entry: Fun) -> Program {
+        Program { entry, constants: vec![], symbols: Symbols::new() }
+    }
+
+    /// Makes a fiber that runs the entry function
+    /// with this program's constant pool and symbols.
+    pub fn fiber(&self) -> Fiber {
+        self.fiber_with_captures(self.entry.captures.clone())
+    }
+
+    /// Like `fiber`, but the entry function gets `captures` from the host
+    /// in place of its own, for it to `Get` like any capture.
+    /// They are kept when the fiber is `reset`.
+    pub fn fiber_with_captures(&self, captures: Rc<Vec<Data>>) -> Fiber {
+        let entry = Fun { captures, ..self.entry.clone() };
+        Fiber::new(entry)
+            .with_constants(Rc::new(self.constants.clone()))
+            .with_symbols(Rc::new(self.symbols.clone()))
+    }
+
+    /// Runs the program to completion on a new fiber, as made by `fiber`.
+    pub fn run(&self) -> Result<Data, Effect> {
+        self.fiber().run()
+    }
+
+    /// Encodes the program so it can be loaded later with `from_bytes`.
+    /// Fails if it embeds a continuation.
+    #[cfg(feature = "serde")]
+    pub fn to_bytes(&self) -> Result<Vec<u8>, bincode::Error> {
+        bincode::serialize(self)
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, bincode::Error> {
+        bincode::deserialize(bytes)
+    }
+}
+
+/// A jump target in a `FunBuilder`,
+/// which can be jumped to before it is marked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label(usize);
+
+/// Builds a `Fun` op by op, resolving jumps to labels once it is built.
+#[derive(Debug, Default)]
+pub struct FunBuilder {
+    ops:     Vec<Op>,
+    labels:  Vec<Option<usize>>,
+    patches: Vec<(usize, Label)>,
+}
+
+impl FunBuilder {
+    pub fn new() -> FunBuilder {
+        FunBuilder::default()
+    }
+
+    pub fn push(&mut self, op: Op) -> &mut FunBuilder {
+        self.ops.push(op);
+        self
+    }
+
+    /// Makes a new label, to be placed later with `mark`.
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Places `label` at the next op pushed.
+    pub fn mark(&mut self, label: Label) -> &mut FunBuilder {
+        self.labels[label.0] = Some(self.ops.len());
+        self
+    }
+
+    pub fn jump_to(&mut self, label: Label) -> &mut FunBuilder {
+        self.patches.push((self.ops.len(), label));
+        self.push(Op::Jump(0))
+    }
+
+    pub fn jump_if_false_to(&mut self, label: Label) -> &mut FunBuilder {
+        self.patches.push((self.ops.len(), label));
+        self.push(Op::JumpIfFalse(0))
+    }
+
+    /// Patches every jump to point at its label.
+    /// Panics if a label that is jumped to was never marked.
+    pub fn build(&self) -> Fun {
+        let mut ops = self.ops.clone();
+        for (at, label) in self.patches.iter() {
+            let target = self.labels[label.0]
+                .expect("jump to a label that was never marked");
+            match &mut ops[*at] {
+                Op::Jump(t) | Op::JumpIfFalse(t) => *t = target,
+                _ => unreachable!(),
+            }
+        }
+
+        Fun {
+            ops:      Rc::new(ops),
+            captures: Rc::new(vec![]),
+            spans:    None,
+        }
+    }
+}
+
+/// A position in the source a function was compiled from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    pub line:   usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone)]
 pub struct Suspend {
-    ops: Rc<Vec<Op>>,
-    pc:  usize,
+    ops:   Rc<Vec<Op>>,
+    spans: Option<Rc<Vec<Span>>>,
+    pc:    usize,
 }
 
 impl Suspend {
-    pub fn new(ops: Rc<Vec<Op>>, pc: usize) -> Suspend {
-        Suspend { ops, pc }
+    pub fn new(
+        ops: Rc<Vec<Op>>,
+        spans: Option<Rc<Vec<Span>>>,
+        pc: usize,
+    ) -> Suspend {
+        Suspend { ops, spans, pc }
     }
 }
 
-/// Represents a single function in the process of execution
-#[derive(Debug)]
+/// Represents a single function in the process of execution.
+///
+/// `Get(Name(i))` and `Set(Name(i))` address slot `i` of a frame.
+/// A slot holds the local last `Set` to `i`, if any;
+/// otherwise it falls back to the capture at index `i`.
+/// So setting a slot shadows the capture with the same index
+/// for the remainder of the frame.
+/// Slots past `Frame::MAX_SLOTS` can't be set, so bad bytecode
+/// can't make a frame allocate without bound.
+///
+/// When a frame with a return clause executes `Return`,
+/// the clause is called with the returned value in its place,
+/// or a `Tuple` of the values if there are several,
+/// and whatever the clause returns goes to the frame's caller.
+#[derive(Debug, Clone)]
 struct Frame {
-    suspend:  Option<Suspend>,
-    index:    usize, // index of data on stack, i.e. where this frame is.
-    captures: Rc<Vec<Data>>,
-    handlers: BTreeMap<Name, Fun>,
+    suspend:   Option<Suspend>,
+    /// The height of the datum stack when the frame was entered;
+    /// everything above it is the frame's own, and is discarded
+    /// when the frame returns or is replaced by a tail call.
+    index:     usize,
+    captures:  Rc<Vec<Data>>,
+    locals:    Vec<Option<Data>>,
+    handlers:  BTreeMap<Name, EffectHandler>,
+    on_return: Option<Fun>,
+}
+
+/// An effect handler installed in a frame.
+#[derive(Debug, Clone)]
+struct EffectHandler {
+    fun:     Fun,
+    shallow: bool,
+    /// Set once a shallow handler in a parent fiber has fired,
+    /// as it can't be removed from the shared parent;
+    /// it is then as good as removed.
+    fired:   Cell<bool>,
+}
+
+/// Where `Fiber::resolve_handler` found a handler:
+/// the index of the frame that installed it, in the fiber it names,
+/// or the one searching if `None`.
+struct Resolved {
+    handler: EffectHandler,
+    fiber:   Option<Rc<Fiber>>,
+    frame:   usize,
 }
 
 impl Frame {
+    const MAX_SLOTS: usize = 1 << 16;
+
     pub fn new(
         suspend: Option<Suspend>,
         index: usize,
@@ -65,252 +746,5017 @@ impl Frame {
             suspend,
             index,
             captures,
+            locals: vec![],
             handlers: BTreeMap::new(),
+            on_return: None,
         }
     }
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Data {
+    /// The value of computations with no meaningful result.
+    Unit,
     Float(f64),
+    Int(i64),
+    Bool(bool),
+    Char(char),
+    Str(Rc<str>),
+    /// An immutable buffer of bytes, for binary data.
+    Bytes(Rc<[u8]>),
+    Tuple(Rc<Vec<Data>>),
+    /// A mutable list, shared between all copies of the value.
+    List(Rc<RefCell<Vec<Data>>>),
+    /// A mutable map, shared between all copies of the value.
+    Map(Rc<RefCell<BTreeMap<Key, Data>>>),
     RawFun(RawFun),
     Fun(Fun),
+    /// A live continuation isn't portable,
+    /// so serializing one is an error.
+    #[cfg_attr(feature = "serde", serde(skip))]
     Cont(Rc<Fiber>),
+    /// A function implemented by the host, called like a `Fun`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Native(Native),
 }
 
-impl Data {
-    fn try_math(
-        self,
-        other: Self,
-        binop: fn(f64, f64) -> Result<f64, Effect>
-    ) -> Result<Data, Effect> {
-        match (self, other) {
-            (Data::Float(a), Data::Float(b)) => {
-                Ok(Data::Float(binop(a, b)?))
-            },
-            _ => Err(Effect::TypeMismatch),
-        }
-    }
+/// A key in a `Map`. Only `Int`s and `Str`s can be keys;
+/// `Int`s order before `Str`s, and `Str`s order by their bytes.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Key {
+    Int(i64),
+    Str(Rc<str>),
 }
 
-#[derive(Debug)]
-struct Stack {
-    datum: Vec<Data>,
-    frames: Vec<Frame>,
-}
+impl TryFrom<Data> for Key {
+    type Error = Effect;
 
-impl Stack {
-    pub fn new(captures: Rc<Vec<Data>>) -> Stack {
-        Stack {
-            datum: vec![],
-            frames: vec![Frame::new(None, 0, captures)],
+    fn try_from(data: Data) -> Result<Key, Effect> {
+        match data {
+            Data::Int(n) => Ok(Key::Int(n)),
+            Data::Str(s) => Ok(Key::Str(s)),
+            other => Err(Effect::mismatch("Int or Str", &other)),
         }
     }
 }
 
-#[derive(Debug)]
-pub enum Effect {
-    /// Errors that indicate invalid opcode
-    Fatal,
-    TypeMismatch,
-    ZeroDivision,
-    Virtual(Name, Data),
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Key::Int(n) => write!(f, "{}", n),
+            Key::Str(s) => write!(f, "{}", s),
+        }
+    }
 }
 
-/// Represents a stack of functions in the process of being executed
-#[derive(Debug)]
-pub struct Fiber {
-    parent: Option<Rc<Fiber>>,
-    stack: Stack,
-    ops:   Rc<Vec<Op>>,
-    pc:    usize,
-}
+/// A host function exposed to bytecode,
+/// taking the argument it was called with.
+#[derive(Clone)]
+pub struct Native(pub Rc<dyn Fn(Data) -> Result<Data, Effect>>);
 
-impl Fiber {
-    pub fn new(fun: Fun) -> Fiber {
-        Fiber {
-            parent: None,
-            stack:  Stack::new(fun.captures),
-            ops:    fun.ops,
-            pc:     0,
-        }
+impl Native {
+    pub fn new(f: impl Fn(Data) -> Result<Data, Effect> + 'static) -> Native {
+        Native(Rc::new(f))
     }
+}
 
-    fn push(&mut self, data: Data) {
-        self.stack.datum.push(data)
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Native({:p})", Rc::as_ptr(&self.0))
     }
+}
 
-    fn kill(&mut self) {
-        self.pc = self.ops.len();
-    }
+/// The arithmetic ops, as dispatched by `Data::apply_binop`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
 
-    fn is_done(&self) -> bool {
-        self.pc >= self.ops.len()
+impl Data {
+    /// The name of the value's type, as in its variant.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Data::Unit      => "Unit",
+            Data::Float(_)  => "Float",
+            Data::Int(_)    => "Int",
+            Data::Bool(_)   => "Bool",
+            Data::Char(_)   => "Char",
+            Data::Str(_)    => "Str",
+            Data::Bytes(_)  => "Bytes",
+            Data::Tuple(_)  => "Tuple",
+            Data::List(_)   => "List",
+            Data::Map(_)    => "Map",
+            Data::RawFun(_) => "RawFun",
+            Data::Fun(_)    => "Fun",
+            Data::Cont(_)   => "Cont",
+            Data::Native(_) => "Native",
+        }
     }
 
-    fn next_op(&self) -> Op {
-        self.ops[self.pc].clone()
+    /// Whether the value counts as true, as tested by `ToBool`.
+    /// `Unit`, `false`, zero, including `-0.0`, and empty `Str`s, `Bytes`,
+    /// `Tuple`s, `List`s, and `Map`s are falsey; everything else,
+    /// including `NaN`, functions, and continuations, is truthy.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Data::Unit         => false,
+            Data::Bool(b)      => *b,
+            Data::Float(n)     => *n != 0.0,
+            Data::Int(n)       => *n != 0,
+            Data::Str(s)       => !s.is_empty(),
+            Data::Bytes(bytes) => !bytes.is_empty(),
+            Data::Tuple(items) => !items.is_empty(),
+            Data::List(list)   => !list.borrow().is_empty(),
+            Data::Map(map)     => !map.borrow().is_empty(),
+            _ => true,
+        }
     }
 
-    /// unwraps an item or returns the Fatal effect and kills the fiber
-    fn unwrap_or_fatal<T>(&mut self, item: Option<T>) -> Result<T, Effect> {
-        match item {
-            Some(valid) => Ok(valid),
-            None => {
-                self.kill();
-                Err(Effect::Fatal)
-            },
+    /// The suspended fiber of a `Cont`, to inspect without resuming it,
+    /// or `None` for any other value.
+    /// Its `pc` is the op after the one that captured it,
+    /// where it picks up again, and its `stack_data` what it had pushed.
+    pub fn as_cont(&self) -> Option<&Fiber> {
+        match self {
+            Data::Cont(fiber) => Some(fiber),
+            _ => None,
         }
     }
 
-    fn pop(&mut self) -> Result<Data, Effect> {
-        let top = self.stack.datum.pop();
-        self.unwrap_or_fatal(top)
+    /// Converts a value to JSON for handing to the host:
+    /// `Unit` becomes `null`, `List`s and `Tuple`s arrays,
+    /// and `Map`s objects, which must only have `Str` keys.
+    /// `Char`s become strings and `Bytes` arrays of numbers,
+    /// so they come back from `from_json` as `Str`s and `List`s.
+    /// Non-finite `Float`s, functions, continuations,
+    /// and `List`s and `Map`s that hold themselves have no JSON form,
+    /// and are a type mismatch.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> Result<serde_json::Value, Effect> {
+        self.to_json_within(&mut vec![])
     }
 
-    fn resolve_handler<T>(
-        &self,
-        name: Name,
-        extract: impl Fn(&Frame) -> T,
-    ) -> Option<T> {
-        for frame in self.stack.frames.iter().rev() {
-            if let Some(_) = frame.handlers.get(&name) {
-                return Some(extract(frame));
-            }
-        }
+    /// Like `to_json`, where `open` holds the `List`s and `Map`s
+    /// being converted further up, which can't be converted again.
+    #[cfg(feature = "json")]
+    fn to_json_within(&self, open: &mut Vec<usize>) -> Result<serde_json::Value, Effect> {
+        use serde_json::Value;
 
-        if let Some(parent) = &self.parent {
-            parent.resolve_handler(name, extract)
-        } else {
-            None
+        let unrepresentable = |found| Effect::TypeMismatch {
+            expected: "JSON value",
+            found,
+        };
+        let key = match self {
+            Data::List(list) => Some((Rc::as_ptr(list) as *const () as usize, "cyclic List")),
+            Data::Map(map)   => Some((Rc::as_ptr(map) as *const () as usize, "cyclic Map")),
+            _ => None,
+        };
+        if let Some((key, found)) = key {
+            if open.contains(&key) { return Err(unrepresentable(found)); }
+            open.push(key);
         }
-    }
-
-    pub fn run(&mut self) -> Result<(), Effect> {
-        use Op::*;
 
-        while !self.is_done() {
-            println!("Before: {:#?}", self);
+        let value = match self {
+            Data::Unit     => Value::Null,
+            Data::Float(n) => serde_json::Number::from_f64(*n)
+                .map(Value::Number)
+                .ok_or_else(|| unrepresentable("Float"))?,
+            Data::Int(n)   => Value::from(*n),
+            Data::Bool(b)  => Value::Bool(*b),
+            Data::Char(c)  => Value::String(c.to_string()),
+            Data::Str(s)   => Value::String(s.to_string()),
+            Data::Bytes(bytes) => Value::from(&bytes[..]),
+            Data::Tuple(items) => Value::Array(
+                items.iter()
+                    .map(|item| item.to_json_within(open))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Data::List(list) => Value::Array(
+                list.borrow().iter()
+                    .map(|item| item.to_json_within(open))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Data::Map(map) => Value::Object(
+                map.borrow().iter()
+                    .map(|(key, value)| match key {
+                        Key::Str(key) => Ok((key.to_string(), value.to_json_within(open)?)),
+                        Key::Int(_) => Err(Effect::TypeMismatch {
+                            expected: "Str",
+                            found:    "Int",
+                        }),
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+            other => Err(unrepresentable(other.type_name()))?,
+        };
 
-            match self.next_op() {
-                Const(data) => {
-                    self.push(data.clone());
-                },
+        if key.is_some() { open.pop(); }
+        Ok(value)
+    }
 
-                Add => {
-                    let a = self.pop()?;
-                    let b = self.pop()?;
-                    self.push(Data::try_math(
-                        a, b,
-                        |a, b| Ok(a + b),
-                    )?)
-                },
+    /// Converts JSON from the host to a value, the reverse of `to_json`.
+    /// Numbers become `Int`s if they are integers and `Float`s otherwise;
+    /// an integer too big for an `Int` is an `IntegerOverflow`.
+    #[cfg(feature = "json")]
+    pub fn from_json(json: &serde_json::Value) -> Result<Data, Effect> {
+        use serde_json::Value;
 
-                Div => {
-                    let a = self.pop()?;
-                    let b = self.pop()?;
-                    self.push(Data::try_math(
-                        a, b,
-                        |a, b| if a == 0.0 {
-                            Err(Effect::ZeroDivision)
-                        } else {
-                            Ok(a / b)
-                        },
-                    )?)
-                },
+        Ok(match json {
+            Value::Null    => Data::Unit,
+            Value::Bool(b) => Data::Bool(*b),
+            Value::Number(n) if n.is_f64() => {
+                Data::Float(n.as_f64().unwrap_or(f64::NAN))
+            },
+            Value::Number(n) => match n.as_i64() {
+                Some(n) => Data::Int(n),
+                None    => Err(Effect::IntegerOverflow)?,
+            },
+            Value::String(s) => Data::Str(s.as_str().into()),
+            Value::Array(items) => Data::List(Rc::new(RefCell::new(
+                items.iter().map(Data::from_json).collect::<Result<_, _>>()?,
+            ))),
+            Value::Object(entries) => Data::Map(Rc::new(RefCell::new(
+                entries.iter()
+                    .map(|(key, value)| {
+                        Ok((Key::Str(key.as_str().into()), Data::from_json(value)?))
+                    })
+                    .collect::<Result<_, Effect>>()?,
+            ))),
+        })
+    }
 
-                Handler(name) => {
-                    let fp = match self.pop()? {
-                        Data::Fun(f) => f,
-                        _ => Err(Effect::TypeMismatch)?,
-                    };
+    /// Copies a value along with the `List`s and `Map`s inside it,
+    /// so changing the copy doesn't change the original.
+    /// Values shared within the original are shared within the copy,
+    /// going by the copies made so far in `copies`.
+    /// Continuations are copied as `CloneCont` copies them,
+    /// so the original and the copy can each be resumed;
+    /// what they hold stays shared, as do functions and what they hold.
+    fn deep_copy(&self, copies: &mut BTreeMap<usize, Data>) -> Data {
+        match self {
+            Data::Tuple(items) => Data::Tuple(Rc::new(
+                items.iter().map(|item| item.deep_copy(copies)).collect(),
+            )),
+            Data::List(list) => {
+                let key = Rc::as_ptr(list) as *const () as usize;
+                if let Some(copy) = copies.get(&key) { return copy.clone(); }
 
-                    let mut frames = std::mem::take(&mut self.stack.frames);
-                    self.unwrap_or_fatal(frames.last_mut())?
-                        .handlers.insert(name, fp);
-                    std::mem::swap(&mut self.stack.frames, &mut frames);
-                },
+                // recorded before copying the items, in case they hold the list
+                let copy = Rc::new(RefCell::new(vec![]));
+                copies.insert(key, Data::List(copy.clone()));
+                let items = list.borrow().iter()
+                    .map(|item| item.deep_copy(copies))
+                    .collect();
+                *copy.borrow_mut() = items;
+                Data::List(copy)
+            },
+            Data::Map(map) => {
+                let key = Rc::as_ptr(map) as *const () as usize;
+                if let Some(copy) = copies.get(&key) { return copy.clone(); }
 
-                Raise(name) => {
-                    let fun = self.resolve_handler(
-                        name,
-                        |frame| frame.handlers.get(&name).unwrap().clone(),
-                    );
+                let copy = Rc::new(RefCell::new(BTreeMap::new()));
+                copies.insert(key, Data::Map(copy.clone()));
+                let entries = map.borrow().iter()
+                    .map(|(key, value)| (key.clone(), value.deep_copy(copies)))
+                    .collect();
+                *copy.borrow_mut() = entries;
+                Data::Map(copy)
+            },
+            Data::Cont(fiber) => {
+                let key = Rc::as_ptr(fiber) as *const () as usize;
+                if let Some(copy) = copies.get(&key) { return copy.clone(); }
 
-                    let data = self.pop()?;
-                    let fun = match fun {
-                        Some(f) => f,
-                        None => Err(Effect::Virtual(name, data.clone()))?,
-                    };
+                let copy = Data::Cont(Rc::new((**fiber).clone()));
+                copies.insert(key, copy.clone());
+                copy
+            },
+            other => other.clone(),
+        }
+    }
 
-                    let new_fiber = Fiber::new(fun);
-                    self.switch(new_fiber, data);
-                    continue;
+    /// Applies `op` to two `Float`s or two `Int`s.
+    /// Mixed operands are a type mismatch; there is no implicit coercion.
+    /// Dividing by zero raises `ZeroDivision` for both types,
+    /// and `Int`s raise `IntegerOverflow` rather than wrapping.
+    fn apply_binop(self, other: Self, op: BinOp) -> Result<Data, Effect> {
+        match (self, other) {
+            (Data::Float(a), Data::Float(b)) => {
+                if b == 0.0 && matches!(op, BinOp::Div | BinOp::Mod) {
+                    Err(Effect::ZeroDivision)?
                 }
-
-                Call => {
-                    let arg = self.pop()?;
-                    let fun = self.pop()?;
-
-                    match fun {
-                        Data::Fun(fun) => {
-                            self.call(fun);
-                            self.push(arg);
-                        }
-                        Data::Cont(fiber) => {
-                            let fiber = self.unwrap_or_fatal(Rc::<Fiber>::try_unwrap(fiber).ok())?;
-                            self.switch(fiber, arg);
-                        }
-                        _ => Err(Effect::TypeMismatch)?,
-                    }
+                Ok(Data::Float(match op {
+                    BinOp::Add => a + b,
+                    BinOp::Sub => a - b,
+                    BinOp::Mul => a * b,
+                    BinOp::Div => a / b,
+                    BinOp::Mod => a % b,
+                }))
+            },
+            (Data::Int(a), Data::Int(b)) => {
+                if b == 0 && matches!(op, BinOp::Div | BinOp::Mod) {
+                    Err(Effect::ZeroDivision)?
                 }
+                let result = match op {
+                    BinOp::Add => a.checked_add(b),
+                    BinOp::Sub => a.checked_sub(b),
+                    BinOp::Mul => a.checked_mul(b),
+                    BinOp::Div => a.checked_div(b),
+                    BinOp::Mod => a.checked_rem(b),
+                };
+                Ok(Data::Int(result.ok_or(Effect::IntegerOverflow)?))
+            },
+            (a @ Data::Float(_), b) | (a @ Data::Int(_), b) => {
+                Err(Effect::mismatch(a.type_name(), &b))
+            },
+            (a, _) => Err(Effect::mismatch("Float or Int", &a)),
+        }
+    }
 
-                Pop(times) => {
-                    for _ in 0..times {
-                        self.pop()?;
-                    }
-                }
+    fn try_compare(
+        self,
+        other: Self,
+        test: fn(Ordering) -> bool,
+    ) -> Result<Data, Effect> {
+        let ordering = match (self, other) {
+            (Data::Float(a), Data::Float(b)) => a.partial_cmp(&b),
+            (Data::Int(a),   Data::Int(b))   => Some(a.cmp(&b)),
+            (Data::Char(a),  Data::Char(b))  => Some(a.cmp(&b)),
+            (a @ Data::Float(_), b)
+            | (a @ Data::Int(_), b)
+            | (a @ Data::Char(_), b) => Err(Effect::mismatch(a.type_name(), &b))?,
+            (a, _) => Err(Effect::mismatch("Float, Int, or Char", &a))?,
+        };
+        // NaN is unordered, so any comparison with it is false
+        Ok(Data::Bool(ordering.is_some_and(test)))
+    }
+}
 
-                Capture => {
-                    let raw_fun = match self.pop()? {
-                        Data::RawFun(r) => r,
-                        _ => Err(Effect::TypeMismatch)?,
-                    };
+/// Values compare structurally, except for the following:
+///
+/// - `Float`s follow IEEE 754, so `NaN != NaN` and `0.0 == -0.0`.
+/// - Functions and continuations compare by identity,
+///   so only copies of the same value are equal.
+/// - Values of different types are never equal:
+///   there is no coercion, so `Int(1) != Float(1.0)`.
+/// - `List`s and `Map`s that hold themselves are equal
+///   if no difference can be found by following them.
+impl PartialEq for Data {
+    fn eq(&self, other: &Data) -> bool {
+        self.eq_within(other, &mut BTreeSet::new())
+    }
+}
 
-                    self.stack.datum.split_off(
-                        self.stack.datum.len().try_sub(raw_fun.num_captures)
-                    )
+impl Data {
+    /// Compares as `==` does, taking the pairs of `List`s and `Map`s
+    /// in `assumed`, which are being compared further up, to be equal,
+    /// so comparing values that hold themselves comes to an end.
+    fn eq_within(&self, other: &Data, assumed: &mut BTreeSet<(usize, usize)>) -> bool {
+        match (self, other) {
+            (Data::Unit,      Data::Unit)      => true,
+            (Data::Float(a),  Data::Float(b))  => a == b,
+            (Data::Int(a),    Data::Int(b))    => a == b,
+            (Data::Bool(a),   Data::Bool(b))   => a == b,
+            (Data::Char(a),   Data::Char(b))   => a == b,
+            (Data::Str(a),    Data::Str(b))    => a == b,
+            (Data::Bytes(a),  Data::Bytes(b))  => a == b,
+            (Data::Tuple(a),  Data::Tuple(b))  => {
+                a.len() == b.len()
+                && a.iter().zip(b.iter()).all(|(a, b)| a.eq_within(b, assumed))
+            },
+            (Data::List(a),   Data::List(b))   => {
+                let a_key = Rc::as_ptr(a) as *const () as usize;
+                let b_key = Rc::as_ptr(b) as *const () as usize;
+                if !assumed.insert((a_key, b_key)) { return true; }
+                let (a, b) = (a.borrow(), b.borrow());
+                a.len() == b.len()
+                && a.iter().zip(b.iter()).all(|(a, b)| a.eq_within(b, assumed))
+            },
+            (Data::Map(a),    Data::Map(b))    => {
+                let a_key = Rc::as_ptr(a) as *const () as usize;
+                let b_key = Rc::as_ptr(b) as *const () as usize;
+                if !assumed.insert((a_key, b_key)) { return true; }
+                let (a, b) = (a.borrow(), b.borrow());
+                a.len() == b.len()
+                && a.iter().zip(b.iter()).all(|((ka, a), (kb, b))| {
+                    ka == kb && a.eq_within(b, assumed)
+                })
+            },
+            (Data::RawFun(a), Data::RawFun(b)) => Rc::ptr_eq(&a.ops, &b.ops),
+            (Data::Fun(a),    Data::Fun(b))    => a.same(b),
+            (Data::Cont(a),   Data::Cont(b))   => Rc::ptr_eq(a, b),
+            (Data::Native(a), Data::Native(b)) => Rc::ptr_eq(&a.0, &b.0),
+            _ => false,
+        }
+    }
+}
 
-                    todo!()
-                }
+/// Renders a value for humans: `Str`s are written without quotes,
+/// `Float`s always keep a decimal point to set them apart from `Int`s,
+/// and values without a textual form render as `<fun>` and the like.
+/// A `List` or `Map` inside itself renders as `[...]` or `{...}`.
+impl fmt::Display for Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_within(f, &mut vec![])
+    }
+}
 
-                _ => todo!(),
+impl Data {
+    /// Renders as `Display` does, where `open` holds the `List`s
+    /// and `Map`s being rendered further up, which aren't rendered again.
+    fn write_within(&self, f: &mut fmt::Formatter, open: &mut Vec<usize>) -> fmt::Result {
+        let key = match self {
+            Data::List(l) => Some(Rc::as_ptr(l) as *const () as usize),
+            Data::Map(m)  => Some(Rc::as_ptr(m) as *const () as usize),
+            _ => None,
+        };
+        if let Some(key) = key {
+            if open.contains(&key) {
+                return match self {
+                    Data::List(_) => write!(f, "[...]"),
+                    _             => write!(f, "{{...}}"),
+                };
             }
-
-            println!("After: {:#?}", self);
-            self.pc += 1;
+            open.push(key);
         }
-
+        self.write_open(f, open)?;
+        if key.is_some() { open.pop(); }
         Ok(())
     }
 
-    pub fn switch(&mut self, other_fiber: Fiber, data: Data) {
-        let old_fiber = std::mem::replace(self, other_fiber);
-        let cont = Data::Cont(Rc::new(old_fiber));
-        self.push(cont);
-        self.push(data);
-    }
-
-    pub fn call(&mut self, fun: Fun) {
-        let old_ops = std::mem::replace(&mut self.ops, fun.ops);
-        let old_pc  = std::mem::replace(&mut self.pc,  0);
-        let suspend = Suspend::new(old_ops, old_pc);
+    /// Renders the value itself, for `write_within`.
+    fn write_open(&self, f: &mut fmt::Formatter, open: &mut Vec<usize>) -> fmt::Result {
+        match self {
+            Data::Unit      => write!(f, "()"),
+            Data::Float(n)  => write!(f, "{:?}", n),
+            Data::Int(n)    => write!(f, "{}", n),
+            Data::Bool(b)   => write!(f, "{}", b),
+            Data::Char(c)   => write!(f, "{}", c),
+            Data::Str(s)    => write!(f, "{}", s),
+            Data::Bytes(b)  => write!(f, "b\"{}\"", b.escape_ascii()),
+            Data::Tuple(t)  => {
+                write!(f, "(")?;
+                write_items(f, t.iter(), open)?;
+                write!(f, ")")
+            },
+            Data::List(l)   => {
+                write!(f, "[")?;
+                write_items(f, l.borrow().iter(), open)?;
+                write!(f, "]")
+            },
+            Data::Map(m)    => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in m.borrow().iter().enumerate() {
+                    if i > 0 { write!(f, ", ")?; }
+                    write!(f, "{}: ", key)?;
+                    value.write_within(f, open)?;
+                }
+                write!(f, "}}")
+            },
+            Data::RawFun(_) => write!(f, "<rawfun>"),
+            Data::Fun(_)    => write!(f, "<fun>"),
+            Data::Cont(_)   => write!(f, "<cont>"),
+            Data::Native(_) => write!(f, "<native>"),
+        }
+    }
+}
+
+fn write_items<'a>(
+    f: &mut fmt::Formatter,
+    items: impl Iterator<Item = &'a Data>,
+    open: &mut Vec<usize>,
+) -> fmt::Result {
+    for (i, item) in items.enumerate() {
+        if i > 0 { write!(f, ", ")?; }
+        item.write_within(f, open)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+struct Stack {
+    datum: Vec<Data>,
+    frames: Vec<Frame>,
+}
+
+impl Stack {
+    /// Makes a stack with room for `datum_cap` values
+    /// and `frame_cap` frames before it has to grow.
+    pub fn new(
+        captures: Rc<Vec<Data>>,
+        datum_cap: usize,
+        frame_cap: usize,
+    ) -> Stack {
+        let mut frames = Vec::with_capacity(frame_cap.max(1));
+        frames.push(Frame::new(None, 0, captures));
+        Stack { datum: Vec::with_capacity(datum_cap), frames }
+    }
+}
+
+#[derive(Debug)]
+pub enum Effect {
+    /// Errors that indicate invalid opcode,
+    /// with the `pc` of that op and where it came from, if known,
+    /// and the fiber's `backtrace` when it happened.
+    Fatal { pc: usize, span: Option<Span>, backtrace: Vec<BacktraceEntry> },
+    /// An op was given a value of the wrong type,
+    /// named as in `Data::type_name`.
+    TypeMismatch { expected: &'static str, found: &'static str },
+    ZeroDivision,
+    /// `Int` arithmetic whose result doesn't fit in an `i64`.
+    /// It never wraps, whatever the build profile.
+    IntegerOverflow,
+    IndexOutOfBounds { index: i64, len: usize },
+    /// A `MapGet` of a key that isn't in the map.
+    MissingKey(Key),
+    /// A continuation was resumed a second time.
+    ContinuationAlreadyUsed,
+    /// A handler was installed for an effect
+    /// that the current frame already handles.
+    DuplicateHandler(Name),
+    /// An effect was raised with an argument, or resumed with a value,
+    /// of a type other than its `Signature` gives,
+    /// named as in `Data::type_name`.
+    EffectTypeMismatch { name: Name, expected: &'static str, found: &'static str },
+    /// An effect was raised with a different number of arguments
+    /// than its `Signature` gives.
+    EffectArityMismatch { name: Name, expected: usize, found: usize },
+    /// An `Int` that isn't a Unicode scalar value was made a `Char`.
+    InvalidChar(i64),
+    /// A `BytesToStr` of bytes that aren't valid UTF-8,
+    /// with the length of the valid prefix.
+    InvalidUtf8 { valid_up_to: usize },
+    /// A call would nest more frames than the fiber's `max_depth`.
+    StackOverflow,
+    /// An op would have pushed more values than the fiber's `max_stack`.
+    DatumOverflow,
+    /// An `Assert` or `AssertMsg` of `false`, at `pc`, with its message, if any.
+    AssertionFailed { pc: usize, message: Option<Rc<str>> },
+    /// A `StrRepeat` with a count below zero.
+    NegativeCount(i64),
+    /// A `StrRepeat` would make a `Str` of `len` bytes,
+    /// longer than the fiber's `max_str_len`.
+    StrTooLong { len: usize, max: usize },
+    /// Writing the output of a `Print` failed.
+    Io(OutputError),
+    /// The fiber ran out of fuel before finishing.
+    /// It can be continued after topping it up with `add_fuel`.
+    OutOfFuel,
+    /// A `Yield` paused the fiber, so the host can do other work.
+    /// It can be continued with `resume`.
+    Yielded,
+    /// An effect no handler was installed for, with its arguments,
+    /// and its symbol, if the fiber knows it.
+    Virtual {
+        name:   Name,
+        symbol: Option<Rc<str>>,
+        args:   Vec<Data>,
+    },
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Effect::Fatal { pc, span, .. } => {
+                write!(f, "fatal error: invalid bytecode at pc {}", pc)?;
+                if let Some(Span { line, column }) = span {
+                    write!(f, " (line {}, column {})", line, column)?;
+                }
+                Ok(())
+            },
+            Effect::TypeMismatch { expected, found } => {
+                write!(f, "type mismatch: expected {}, found {}", expected, found)
+            },
+            Effect::ZeroDivision => write!(f, "division by zero"),
+            Effect::IntegerOverflow => write!(f, "integer overflow"),
+            Effect::IndexOutOfBounds { index, len } => {
+                write!(f, "index {} out of bounds for length {}", index, len)
+            },
+            Effect::MissingKey(key) => write!(f, "missing key {}", key),
+            Effect::ContinuationAlreadyUsed => {
+                write!(f, "continuation has already been resumed")
+            },
+            Effect::DuplicateHandler(Name(name)) => {
+                write!(f, "handler for effect {} is already installed", name)
+            },
+            Effect::EffectTypeMismatch { name: Name(name), expected, found } => {
+                write!(
+                    f, "effect {} type mismatch: expected {}, found {}",
+                    name, expected, found,
+                )
+            },
+            Effect::EffectArityMismatch { name: Name(name), expected, found } => {
+                write!(
+                    f, "effect {} takes {} arguments, but was raised with {}",
+                    name, expected, found,
+                )
+            },
+            Effect::InvalidChar(n) => write!(f, "{} is not a valid char", n),
+            Effect::InvalidUtf8 { valid_up_to } => {
+                write!(f, "invalid UTF-8 after byte {}", valid_up_to)
+            },
+            Effect::StackOverflow => write!(f, "stack overflow"),
+            Effect::DatumOverflow => write!(f, "too many values on the stack"),
+            Effect::NegativeCount(n) => write!(f, "negative count {}", n),
+            Effect::AssertionFailed { pc, message } => {
+                write!(f, "assertion failed at pc {}", pc)?;
+                match message {
+                    Some(message) => write!(f, ": {}", message),
+                    None => Ok(()),
+                }
+            },
+            Effect::StrTooLong { len, max } => {
+                write!(f, "string of {} bytes is longer than {}", len, max)
+            },
+            Effect::OutOfFuel     => write!(f, "out of fuel"),
+            Effect::Yielded       => write!(f, "yielded"),
+            Effect::Io(kind) => write!(f, "could not write output: {:?}", kind),
+            Effect::Virtual { name: Name(name), symbol, args } => {
+                write!(f, "unhandled effect ")?;
+                match symbol {
+                    Some(symbol) => write!(f, "{} ({})", symbol, name)?,
+                    None => write!(f, "{}", name)?,
+                }
+                write!(f, " with arguments (")?;
+                write_items(f, args.iter(), &mut vec![])?;
+                write!(f, ")")
+            },
+        }
+    }
+}
+
+impl Effect {
+    fn mismatch(expected: &'static str, found: &Data) -> Effect {
+        Effect::TypeMismatch { expected, found: found.type_name() }
+    }
+
+    /// Reports the first of a pair of operands that isn't
+    /// of the type it is expected to be.
+    fn mismatch_pair(
+        expected: (&'static str, &'static str),
+        found: (&Data, &Data),
+    ) -> Effect {
+        if found.0.type_name() != expected.0 {
+            Effect::mismatch(expected.0, found.0)
+        } else {
+            Effect::mismatch(expected.1, found.1)
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Effect {}
+
+type HostFn = Box<dyn FnMut(Data) -> Result<Data, Effect>>;
+
+/// How many times each kind of op has run,
+/// with the first op of each kind run, for naming it.
+#[cfg(feature = "std")]
+type OpCounts = HashMap<Discriminant<Op>, (Op, u64)>;
+
+/// Host callbacks for effects, shared by every fiber that runs
+/// on behalf of the same host.
+#[derive(Clone, Default)]
+struct Hosts(Rc<RefCell<BTreeMap<Name, HostFn>>>);
+
+impl fmt::Debug for Hosts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.0.borrow().keys()).finish()
+    }
+}
+
+/// What `Print` writes to: an `io::Write` with `std`,
+/// and a `fmt::Write` without it.
+#[cfg(feature = "std")]
+pub type Sink = dyn io::Write;
+#[cfg(not(feature = "std"))]
+pub type Sink = dyn fmt::Write;
+
+/// Why writing to a `Sink` failed.
+#[cfg(feature = "std")]
+pub type OutputError = io::ErrorKind;
+#[cfg(not(feature = "std"))]
+pub type OutputError = fmt::Error;
+
+/// Where `Print` writes to.
+#[derive(Clone)]
+struct Output(Rc<RefCell<Sink>>);
+
+#[cfg(feature = "std")]
+impl Default for Output {
+    fn default() -> Output {
+        Output(Rc::new(RefCell::new(io::stdout())))
+    }
+}
+
+/// Without `std` there is no stdout, so output is discarded by default.
+#[cfg(not(feature = "std"))]
+impl Default for Output {
+    fn default() -> Output {
+        struct Discard;
+
+        impl fmt::Write for Discard {
+            fn write_str(&mut self, _: &str) -> fmt::Result {
+                Ok(())
+            }
+        }
+
+        Output(Rc::new(RefCell::new(Discard)))
+    }
+}
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Output({:p})", Rc::as_ptr(&self.0))
+    }
+}
+
+/// How many distinct `Rc`s of one kind `Fiber::live_rc_report` found,
+/// and the sum of their strong counts.
+#[cfg(debug_assertions)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RcCount {
+    pub values: usize,
+    pub strong: usize,
+}
+
+/// Walks what a fiber holds for `Fiber::live_rc_report`,
+/// visiting each `Rc` once, so shared and cyclic values are counted once.
+#[cfg(debug_assertions)]
+#[derive(Default)]
+struct RcWalk {
+    seen:   BTreeSet<usize>,
+    report: BTreeMap<&'static str, RcCount>,
+}
+
+#[cfg(debug_assertions)]
+impl RcWalk {
+    /// Counts `rc` under `kind`,
+    /// returning whether it hadn't been seen before.
+    fn visit<T: ?Sized>(&mut self, kind: &'static str, rc: &Rc<T>) -> bool {
+        if !self.seen.insert(Rc::as_ptr(rc) as *const u8 as usize) {
+            return false;
+        }
+        let count = self.report.entry(kind).or_default();
+        count.values += 1;
+        count.strong += Rc::strong_count(rc);
+        true
+    }
+
+    fn data(&mut self, data: &Data) {
+        match data {
+            Data::Unit
+            | Data::Float(_)
+            | Data::Int(_)
+            | Data::Bool(_)
+            | Data::Char(_) => (),
+            Data::Str(s)    => { self.visit("Str", s); },
+            Data::Bytes(b)  => { self.visit("Bytes", b); },
+            Data::Native(n) => { self.visit("Native", &n.0); },
+            Data::Tuple(items) => if self.visit("Tuple", items) {
+                items.iter().for_each(|item| self.data(item));
+            },
+            Data::List(items) => if self.visit("List", items) {
+                items.borrow().iter().for_each(|item| self.data(item));
+            },
+            Data::Map(map) => if self.visit("Map", map) {
+                map.borrow().values().for_each(|item| self.data(item));
+            },
+            Data::RawFun(raw) => { self.visit("RawFun", &raw.ops); },
+            Data::Fun(fun) => self.fun(fun),
+            Data::Cont(fiber) => if self.visit("Cont", fiber) {
+                self.fiber(fiber);
+            },
+        }
+    }
+
+    /// A `Fun` is counted by its captures, which are what it retains.
+    fn fun(&mut self, fun: &Fun) {
+        if self.visit("Fun", &fun.captures) {
+            fun.captures.iter().for_each(|item| self.data(item));
+        }
+    }
+
+    fn fiber(&mut self, fiber: &Fiber) {
+        fiber.stack.datum.iter().for_each(|item| self.data(item));
+        for frame in fiber.stack.frames.iter() {
+            frame.captures.iter().for_each(|item| self.data(item));
+            frame.locals.iter().flatten().for_each(|item| self.data(item));
+            frame.handlers.values().for_each(|handler| self.fun(&handler.fun));
+            if let Some(on_return) = &frame.on_return {
+                self.fun(on_return);
+            }
+        }
+        if let Some(resumer) = &fiber.resumer {
+            self.fiber(resumer);
+        }
+    }
+}
+
+/// A function in the middle of running, as listed by `Fiber::backtrace`.
+#[derive(Clone)]
+pub struct BacktraceEntry {
+    /// The ops of the function, which identify it,
+    /// as they are shared by every copy of it.
+    pub ops:  Rc<Vec<Op>>,
+    /// Where in `ops` the function is: the op running,
+    /// for the innermost function of each fiber,
+    /// and the op it carries on at once its callee returns otherwise.
+    pub pc:   usize,
+    pub span: Option<Span>,
+}
+
+impl fmt::Debug for BacktraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BacktraceEntry")
+            .field("ops", &Rc::as_ptr(&self.ops))
+            .field("pc", &self.pc)
+            .field("span", &self.span)
+            .finish()
+    }
+}
+
+/// A single op executed by a fiber, as yielded by `Fiber::events`.
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub pc:    usize,
+    pub op:    Op,
+    /// The number of values on the stack after the op.
+    pub depth: usize,
+    /// The result of the fiber, if this op finished it.
+    pub result: Option<Data>,
+}
+
+/// Where a fiber is after `Fiber::step`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    Running,
+    /// The op was a `Yield`, so the host can do other work;
+    /// the next step carries on where it left off.
+    Yielded,
+    /// The fiber finished, with the value left on top of the stack.
+    Done(Data),
+}
+
+/// How a fiber run with `Fiber::run_suspending` stopped.
+#[derive(Debug)]
+pub enum Outcome {
+    Done(Data),
+    /// An effect with no handler paused the fiber.
+    Suspended(Suspension),
+    /// The fiber ran a `Yield`; `run_suspending` carries it on.
+    Yielded(Box<Fiber>),
+}
+
+/// The types of the values an effect is raised and resumed with,
+/// named as in `Data::type_name`, for a fiber to check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub args:   Vec<&'static str>,
+    /// The type of the result of the raise, if it is checked.
+    pub resume: Option<&'static str>,
+}
+
+impl Signature {
+    /// Fails if `data` isn't of the type the effect `name` resumes with.
+    fn check_resume(&self, name: Name, data: &Data) -> Result<(), Effect> {
+        match self.resume {
+            Some(expected) if expected != data.type_name() => {
+                Err(Effect::EffectTypeMismatch {
+                    name,
+                    expected,
+                    found: data.type_name(),
+                })
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A fiber paused by an effect that escaped to the host,
+/// which the host can handle and answer with `resume`.
+#[derive(Debug)]
+pub struct Suspension {
+    pub name:   Name,
+    pub symbol: Option<Rc<str>>,
+    pub args:   Vec<Data>,
+    fiber:      Box<Fiber>,
+}
+
+impl Suspension {
+    /// The fiber that raised the effect.
+    pub fn fiber(&self) -> &Fiber {
+        &self.fiber
+    }
+
+    /// Continues the fiber with `value` as the result of the raise.
+    pub fn resume(mut self, value: Data) -> Result<Outcome, Effect> {
+        if let Some(signature) = self.fiber.signatures.get(&self.name) {
+            signature.check_resume(self.name, &value)?;
+        }
+        self.fiber.push(value)?;
+        self.fiber.pc += 1;
+        self.fiber.run_suspending()
+    }
+}
+
+/// Represents a stack of functions in the process of being executed
+#[derive(Debug, Clone)]
+pub struct Fiber {
+    /// Held strongly, so a handler that drops its continuation
+    /// still has the handlers further out to raise effects to.
+    parent: Option<Rc<Fiber>>,
+    /// How many of the parent's frames, from the bottom,
+    /// may handle this fiber's effects; a handler's fiber
+    /// doesn't see the frame that installed it, or those above.
+    parent_frames: usize,
+    stack: Stack,
+    ops:   Rc<Vec<Op>>,
+    spans: Option<Rc<Vec<Span>>>,
+    pc:    usize,
+    trace: bool,
+    max_depth: usize,
+    max_stack: usize,
+    max_str_len: usize,
+    /// How many more ops may run, if limited.
+    fuel: Option<u64>,
+    hosts: Hosts,
+    /// The signatures effects are checked against, if they have one.
+    signatures: Rc<BTreeMap<Name, Signature>>,
+    /// The effect this fiber is suspended on as a continuation,
+    /// and what it must be resumed with, if that is checked.
+    expects: Option<(Name, Signature)>,
+    constants: Rc<Vec<Data>>,
+    output: Output,
+    symbols: Rc<Symbols>,
+    /// The function the host started running, for `reset`.
+    entry: Fun,
+    /// Set once this fiber has been resumed as a continuation.
+    resumed: Cell<bool>,
+    /// The fiber that resumed this one as a continuation,
+    /// which carries on with this fiber's result once it finishes.
+    resumer: Option<Box<Fiber>>,
+    /// How many times each kind of op has run, if profiling.
+    #[cfg(feature = "std")]
+    counts: Option<Box<OpCounts>>,
+}
+
+/// Fibers own the fibers waiting on them, and continuations,
+/// which may own more in turn, so they are dropped in a loop
+/// rather than recursively, lest a long chain overflow the native stack.
+impl Drop for Fiber {
+    fn drop(&mut self) {
+        let mut chain = vec![];
+        self.unlink(&mut chain);
+        while let Some(mut fiber) = chain.pop() {
+            fiber.unlink(&mut chain);
+        }
+    }
+}
+
+impl Fiber {
+    pub const DEFAULT_MAX_DEPTH: usize = 10_000;
+    pub const DEFAULT_MAX_STACK: usize = 1_000_000;
+    pub const DEFAULT_MAX_STR_LEN: usize = 16 * 1024 * 1024;
+
+    pub fn new(fun: Fun) -> Fiber {
+        Fiber::with_capacity(fun, 0, 0)
+    }
+
+    /// Like `new`, but with room for `datum_cap` values
+    /// and `frame_cap` frames on the stack before it has to grow,
+    /// for hosts that know how deep their programs get.
+    pub fn with_capacity(
+        fun: Fun,
+        datum_cap: usize,
+        frame_cap: usize,
+    ) -> Fiber {
+        Fiber {
+            parent: None,
+            parent_frames: usize::MAX,
+            stack:  Stack::new(fun.captures.clone(), datum_cap, frame_cap),
+            ops:    fun.ops.clone(),
+            spans:  fun.spans.clone(),
+            pc:     0,
+            entry:  fun,
+            trace:  false,
+            max_depth: Fiber::DEFAULT_MAX_DEPTH,
+            max_stack: Fiber::DEFAULT_MAX_STACK,
+            max_str_len: Fiber::DEFAULT_MAX_STR_LEN,
+            fuel:   None,
+            hosts:  Hosts::default(),
+            signatures: Rc::new(BTreeMap::new()),
+            expects: None,
+            constants: Rc::new(vec![]),
+            output: Output::default(),
+            symbols: Rc::new(Symbols::new()),
+            resumed: Cell::new(false),
+            resumer: None,
+            #[cfg(feature = "std")]
+            counts: None,
+        }
+    }
+
+    /// Nests this fiber within `parent`,
+    /// so effects it doesn't handle itself are handled by `parent`.
+    /// The fiber keeps `parent` alive for as long as it is nested.
+    pub fn with_parent(mut self, parent: &Rc<Fiber>) -> Fiber {
+        self.parent = Some(parent.clone());
+        self.parent_frames = usize::MAX;
+        self
+    }
+
+    /// Enables or disables counting how many times each kind of op runs,
+    /// for `profile_report`. Enabling it starts the counts over.
+    #[cfg(feature = "std")]
+    pub fn with_profile(mut self, profile: bool) -> Fiber {
+        self.counts = if profile { Some(Box::default()) } else { None };
+        self
+    }
+
+    /// Enables or disables writing each op to the fiber's output,
+    /// where `Print` writes, as it executes.
+    pub fn with_trace(mut self, trace: bool) -> Fiber {
+        self.trace = trace;
+        self
+    }
+
+    /// Sets how many frames may be nested, across this fiber,
+    /// its parents, and the fibers waiting on it as a continuation,
+    /// before a call, raise, or resume raises `StackOverflow`.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Fiber {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Sets how many values this fiber's stack may hold;
+    /// an op that would push past it raises `DatumOverflow` instead.
+    pub fn with_max_stack(mut self, max_stack: usize) -> Fiber {
+        self.max_stack = max_stack;
+        self
+    }
+
+    /// Sets how long, in bytes, a `StrRepeat` may make a `Str`
+    /// before it raises `StrTooLong`.
+    pub fn with_max_str_len(mut self, max_str_len: usize) -> Fiber {
+        self.max_str_len = max_str_len;
+        self
+    }
+
+    /// Sets the constant pool `ConstIdx` reads from.
+    pub fn with_constants(mut self, constants: Rc<Vec<Data>>) -> Fiber {
+        self.constants = constants;
+        self
+    }
+
+    /// Sends the output of `Print` and tracing to `output` instead of stdout.
+    pub fn with_output(mut self, output: Rc<RefCell<Sink>>) -> Fiber {
+        self.output = Output(output);
+        self
+    }
+
+    /// Sets the symbols used to describe `Name`s, as in `name_of`.
+    pub fn with_symbols(mut self, symbols: Rc<Symbols>) -> Fiber {
+        self.symbols = symbols;
+        self
+    }
+
+    /// Looks up the symbol `name` was given in the program, if any.
+    pub fn name_of(&self, name: Name) -> Option<&str> {
+        self.symbols.name_of(name)
+    }
+
+    /// Handles effect `name` with a host callback
+    /// whenever no handler in the fiber chain does.
+    /// The callback receives the effect's argument,
+    /// or a `Tuple` of them if there isn't exactly one,
+    /// and what it returns becomes the result of the raise.
+    pub fn with_host(
+        self,
+        name: Name,
+        host: impl FnMut(Data) -> Result<Data, Effect> + 'static,
+    ) -> Fiber {
+        self.hosts.0.borrow_mut().insert(name, Box::new(host));
+        self
+    }
+
+    /// Checks that effect `name` is raised with the arguments
+    /// `signature` gives, and resumed with the value it gives,
+    /// raising `EffectTypeMismatch` or `EffectArityMismatch` if not.
+    /// Effects without a signature aren't checked.
+    pub fn with_signature(mut self, name: Name, signature: Signature) -> Fiber {
+        Rc::make_mut(&mut self.signatures).insert(name, signature);
+        self
+    }
+
+    /// Limits the fiber to running `fuel` ops,
+    /// after which it stops with `OutOfFuel`.
+    /// It only ever stops between ops, so its stack and handlers,
+    /// and the fibers it switched from, are as they were;
+    /// after `add_fuel`, `run` carries on as if it had never stopped.
+    pub fn with_fuel(mut self, fuel: u64) -> Fiber {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    /// Gives the fiber `fuel` more ops to run.
+    /// Does nothing if its fuel is unlimited.
+    pub fn add_fuel(&mut self, fuel: u64) {
+        if let Some(left) = &mut self.fuel {
+            *left = left.saturating_add(fuel);
+        }
+    }
+
+    /// The number of frames in this fiber, its parents,
+    /// and the fibers waiting on it to finish as their continuation.
+    fn depth(&self) -> usize {
+        // a loop rather than recursion, so that long chains of fibers
+        // don't overflow the native stack.
+        let mut depth = self.stack.frames.len();
+        let mut parent = self.parent.as_deref();
+        while let Some(fiber) = parent {
+            depth += fiber.stack.frames.len();
+            parent = fiber.parent.as_deref();
+        }
+        let mut resumer = self.resumer.as_deref();
+        while let Some(fiber) = resumer {
+            depth += fiber.stack.frames.len();
+            resumer = fiber.resumer.as_deref();
+        }
+        depth
+    }
+
+    /// The functions in the middle of running, innermost first,
+    /// through this fiber's frames and then its parents'.
+    pub fn backtrace(&self) -> Vec<BacktraceEntry> {
+        let mut backtrace = vec![];
+        self.backtrace_into(&mut backtrace);
+        let mut parent = self.parent.as_deref();
+        while let Some(fiber) = parent {
+            fiber.backtrace_into(&mut backtrace);
+            parent = fiber.parent.as_deref();
+        }
+        backtrace
+    }
+
+    /// Adds this fiber's part of `backtrace`, not its parents'.
+    fn backtrace_into(&self, backtrace: &mut Vec<BacktraceEntry>) {
+        backtrace.push(BacktraceEntry {
+            ops:  self.ops.clone(),
+            pc:   self.pc,
+            span: self.span(),
+        });
+
+        // each frame returns to where its caller carries on
+        for frame in self.stack.frames.iter().rev() {
+            if let Some(suspend) = &frame.suspend {
+                backtrace.push(BacktraceEntry {
+                    ops:  suspend.ops.clone(),
+                    pc:   suspend.pc,
+                    span: suspend.spans.as_ref()
+                        .and_then(|spans| spans.get(suspend.pc))
+                        .copied(),
+                });
+            }
+        }
+    }
+
+    /// Makes a copy of the fiber that runs independently of it,
+    /// for restoring later by replacing the fiber with the copy.
+    /// Mutable values and continuations on the stack and in frames
+    /// are copied too, as described in `Data::deep_copy`,
+    /// as are those of the fibers waiting on this one; the ops stay shared.
+    pub fn snapshot(&self) -> Fiber {
+        let mut copies = BTreeMap::new();
+        let mut snapshot = self.clone();
+
+        let mut next = Some(&mut snapshot);
+        while let Some(fiber) = next {
+            for data in fiber.stack.datum.iter_mut() {
+                *data = data.deep_copy(&mut copies);
+            }
+            for frame in fiber.stack.frames.iter_mut() {
+                for local in frame.locals.iter_mut().flatten() {
+                    *local = local.deep_copy(&mut copies);
+                }
+                frame.captures = Rc::new(
+                    frame.captures.iter()
+                        .map(|capture| capture.deep_copy(&mut copies))
+                        .collect(),
+                );
+            }
+
+            // a handler's parent is usually the continuation it holds,
+            // so the copy's parent is the copy of the continuation
+            let parent = fiber.parent.as_ref()
+                .and_then(|parent| copies.get(&(Rc::as_ptr(parent) as *const () as usize)));
+            if let Some(Data::Cont(parent)) = parent {
+                fiber.parent = Some(parent.clone());
+            }
+            next = fiber.resumer.as_deref_mut();
+        }
+
+        snapshot
+    }
+
+    /// Sets the fiber up to run its entry function again
+    /// from the start, as it was when it was made.
+    /// Settings, like fuel and host callbacks, are kept,
+    /// as is the room the stack has grown to.
+    pub fn reset(&mut self) {
+        self.stack = Stack::new(
+            self.entry.captures.clone(),
+            self.stack.datum.capacity(),
+            self.stack.frames.capacity(),
+        );
+        self.ops   = self.entry.ops.clone();
+        self.spans = self.entry.spans.clone();
+        self.pc    = 0;
+        self.resumed.set(false);
+        self.resumer = None;
+        self.expects = None;
+    }
+
+    /// Sets the fiber up to run `fun`'s ops from the start
+    /// in its base frame, on top of the values and locals it has,
+    /// so what one piece of code leaves behind is there for the next.
+    /// Frames above the base frame, as left by a failed call, are dropped.
+    /// The base frame keeps its captures, so `fun`'s are ignored.
+    pub fn load(&mut self, fun: Fun) {
+        self.stack.frames.truncate(1);
+        self.ops   = fun.ops;
+        self.spans = fun.spans;
+        self.pc    = 0;
+    }
+
+    /// The values on the stack, bottom first.
+    pub fn stack_data(&self) -> &[Data] {
+        &self.stack.datum
+    }
+
+    /// The number of frames in this fiber, not counting its parents.
+    pub fn frame_count(&self) -> usize {
+        self.stack.frames.len()
+    }
+
+    /// The index of the op that will execute next in the current function.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// The op that will execute next, if the fiber isn't done.
+    pub fn current_op(&self) -> Option<&Op> {
+        self.ops.get(self.pc)
+    }
+
+    /// The ops of the function running now, which identify it,
+    /// as they are shared by every copy of it.
+    pub fn ops(&self) -> &Rc<Vec<Op>> {
+        &self.ops
+    }
+
+    /// Hands `result` to the fibers waiting on this one to finish,
+    /// as finishing with `result` would, and runs them until they finish,
+    /// returning their result, or `result` if none are waiting.
+    /// This fiber is left as it was, no longer waited on,
+    /// for hosts that stop a fiber at a `Yield` and keep it.
+    pub fn hand_off(&mut self, result: Data) -> Result<Data, Effect> {
+        let resumer = match self.resumer.take() {
+            Some(resumer) => resumer,
+            None => return Ok(result),
+        };
+        let own = self.replace(*resumer);
+        let finished = self.push(result).and_then(|()| self.run());
+        self.replace(own);
+        finished
+    }
+
+    /// How many times each kind of op has run since profiling was enabled,
+    /// named like in `disassemble`, most frequent first.
+    /// Empty if profiling isn't enabled.
+    #[cfg(feature = "std")]
+    pub fn profile_report(&self) -> Vec<(String, u64)> {
+        let counts = match &self.counts {
+            Some(counts) => counts,
+            None => return vec![],
+        };
+
+        let mut report: Vec<(String, u64)> = counts.values()
+            .map(|(op, count)| {
+                let debug = format!("{:?}", op);
+                let mnemonic = debug.split('(').next().unwrap_or("");
+                (mnemonic.to_string(), *count)
+            })
+            .collect();
+        report.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        report
+    }
+
+    /// Roughly how many `Rc`s of each kind the fiber keeps alive,
+    /// by variant of `Data`, for finding leaked continuations
+    /// and other values held longer than expected.
+    /// Walks the values, locals, captures, and handlers in the stack,
+    /// and into the fibers of continuations and the fiber resuming this one,
+    /// but not its parents.
+    /// Only in debug builds.
+    #[cfg(debug_assertions)]
+    pub fn live_rc_report(&self) -> BTreeMap<&'static str, RcCount> {
+        let mut walk = RcWalk::default();
+        walk.fiber(self);
+        walk.report
+    }
+
+    /// Fails with `DatumOverflow` if `n` more values
+    /// would take the stack past `max_stack`.
+    fn room(&self, n: usize) -> Result<(), Effect> {
+        if self.stack.datum.len().saturating_add(n) > self.max_stack {
+            return Err(Effect::DatumOverflow);
+        }
+        Ok(())
+    }
+
+    /// Pushes `data`, or fails without pushing it
+    /// if the stack is already at `max_stack`.
+    fn push(&mut self, data: Data) -> Result<(), Effect> {
+        self.room(1)?;
+        self.stack.datum.push(data);
+        Ok(())
+    }
+
+    /// Pushes `items` in order, or fails without pushing any of them
+    /// if they would take the stack past `max_stack`.
+    fn extend(
+        &mut self,
+        items: impl ExactSizeIterator<Item = Data>,
+    ) -> Result<(), Effect> {
+        self.room(items.len())?;
+        self.stack.datum.extend(items);
+        Ok(())
+    }
+
+    fn kill(&mut self) {
+        self.pc = self.ops.len();
+    }
+
+    fn is_done(&self) -> bool {
+        self.pc >= self.ops.len()
+    }
+
+    fn next_op(&self) -> &Op {
+        &self.ops[self.pc]
+    }
+
+    /// unwraps an item or returns the Fatal effect and kills the fiber
+    fn unwrap_or_fatal<T>(&mut self, item: Option<T>) -> Result<T, Effect> {
+        match item {
+            Some(valid) => Ok(valid),
+            None => {
+                let fatal = Effect::Fatal {
+                    pc:        self.pc,
+                    span:      self.span(),
+                    backtrace: self.backtrace(),
+                };
+                self.kill();
+                Err(fatal)
+            },
+        }
+    }
+
+    /// The source position of the op at `pc`, if known.
+    fn span(&self) -> Option<Span> {
+        self.spans.as_ref()?.get(self.pc).copied()
+    }
+
+    fn pop(&mut self) -> Result<Data, Effect> {
+        let top = self.stack.datum.pop();
+        self.unwrap_or_fatal(top)
+    }
+
+    fn jump(&mut self, target: usize) -> Result<(), Effect> {
+        let target = if target <= self.ops.len() { Some(target) } else { None };
+        self.pc = self.unwrap_or_fatal(target)?;
+        Ok(())
+    }
+
+    /// Pops the operands of a binary op as `(left, right)`.
+    /// The top of the stack is the right operand,
+    /// so `push a; push b; Div` computes `a / b`.
+    fn pop_pair(&mut self) -> Result<(Data, Data), Effect> {
+        let right = self.pop()?;
+        let left  = self.pop()?;
+        Ok((left, right))
+    }
+
+    /// Pops the top `n` values, returned in stack order,
+    /// so the deepest of them comes first.
+    /// Leaves the stack untouched if it holds fewer than `n` values.
+    fn pop_n(&mut self, n: usize) -> Result<Vec<Data>, Effect> {
+        let split = self.stack.datum.len().checked_sub(n);
+        let split = self.unwrap_or_fatal(split)?;
+        Ok(self.stack.datum.split_off(split))
+    }
+
+    /// Reads slot `index` of the current frame, locals first.
+    fn get_slot(&self, index: usize) -> Option<Data> {
+        let frame = self.stack.frames.last()?;
+        match frame.locals.get(index) {
+            Some(Some(local)) => Some(local.clone()),
+            _ => frame.captures.get(index).cloned(),
+        }
+    }
+
+    /// Finds the innermost handler for `name`,
+    /// searching this fiber's frames from the top down,
+    /// then those of its parent it can see, and so on up the chain.
+    /// When no fiber in the chain handles the effect,
+    /// `raise` falls back to the host callbacks.
+    /// Parents are held strongly, so a handler that discards
+    /// its continuation still raises effects to the handlers further out.
+    fn resolve_handler(&self, name: Name) -> Option<Resolved> {
+        let find = |frames: &[Frame]| frames.iter().enumerate().rev()
+            .find_map(|(index, frame)| {
+                let handler = frame.handlers.get(&name)?;
+                if handler.fired.get() { return None; }
+                Some((index, handler.clone()))
+            });
+
+        if let Some((frame, handler)) = find(&self.stack.frames) {
+            return Some(Resolved { handler, fiber: None, frame });
+        }
+
+        // walks up the parents in a loop, like `depth`.
+        let mut visible = self.parent_frames;
+        let mut parent = self.parent.as_ref();
+        while let Some(fiber) = parent {
+            let frames = &fiber.stack.frames;
+            if let Some((frame, handler)) = find(&frames[..visible.min(frames.len())]) {
+                return Some(Resolved { handler, fiber: Some(fiber.clone()), frame });
+            }
+            visible = fiber.parent_frames;
+            parent = fiber.parent.as_ref();
+        }
+        None
+    }
+
+    /// Steps through the fiber, yielding an `Event` for each op,
+    /// until it is done or an op fails.
+    pub fn events(&mut self) -> impl Iterator<Item = Result<Event, Effect>> + '_ {
+        let mut failed = false;
+        core::iter::from_fn(move || {
+            if failed || self.is_done() { return None; }
+
+            let pc = self.pc;
+            let op = self.next_op().clone();
+            match self.step() {
+                Ok(status) => {
+                    let depth = self.stack.datum.len();
+                    let result = match status {
+                        Status::Done(result) => Some(result),
+                        _ => None,
+                    };
+                    Some(Ok(Event { pc, op, depth, result }))
+                },
+                Err(effect) => {
+                    failed = true;
+                    Some(Err(effect))
+                },
+            }
+        })
+    }
+
+    /// Like `run`, but an effect that no handler or host callback
+    /// handles suspends the fiber rather than failing it,
+    /// so the host can handle it and resume where it left off.
+    /// It also stops at a `Yield`, handing the fiber back to the host.
+    pub fn run_suspending(mut self) -> Result<Outcome, Effect> {
+        loop {
+            match self.step() {
+                Ok(Status::Running) => (),
+                Ok(Status::Yielded) => return Ok(Outcome::Yielded(Box::new(self))),
+                Ok(Status::Done(data)) => return Ok(Outcome::Done(data)),
+                Err(Effect::Virtual { name, symbol, args }) => {
+                    let fiber = Box::new(self);
+                    let suspension = Suspension { name, symbol, args, fiber };
+                    return Ok(Outcome::Suspended(suspension));
+                },
+                Err(effect) => return Err(effect),
+            }
+        }
+    }
+
+    /// Runs the fiber to completion,
+    /// returning the value left on top of the stack.
+    /// A `Yield` stops it with `Effect::Yielded`,
+    /// handing control back to the host until it calls `resume`.
+    pub fn run(&mut self) -> Result<Data, Effect> {
+        loop {
+            match self.step()? {
+                Status::Running => (),
+                Status::Yielded => return Err(Effect::Yielded),
+                Status::Done(result) => return Ok(result),
+            }
+        }
+    }
+
+    /// Carries on running a fiber that stopped at a `Yield`,
+    /// or ran out of fuel, from where it stopped, as `run` does.
+    pub fn resume(&mut self) -> Result<Data, Effect> {
+        self.run()
+    }
+
+    /// Calls `fun` with `args`, as `CallN` would, and runs it
+    /// until it returns, giving back its result.
+    /// The fiber then carries on from where it was before the call,
+    /// so this also works between steps or once the fiber is done.
+    /// Effects are handled by this fiber's handlers as usual.
+    /// If the call fails the fiber is left where it failed.
+    /// If a handler handles an effect of the call, the call returning
+    /// hands its result to the handler, if it resumed, and the handler's
+    /// result is returned instead, leaving the fiber as the handler
+    /// finished it.
+    pub fn call_function(&mut self, fun: Fun, args: Vec<Data>) -> Result<Data, Effect> {
+        if self.depth() >= self.max_depth {
+            return Err(Effect::StackOverflow);
+        }
+
+        let ops   = self.ops.clone();
+        let spans = self.spans.clone();
+        let pc    = self.pc;
+
+        // returning to nothing ends the run with the result on top,
+        // and tells a return apart from a handler that finished.
+        let returned = Rc::new(vec![]);
+        self.room(args.len())?;
+        self.enter(fun, Some(Suspend::new(returned.clone(), None, 0)));
+        self.stack.datum.extend(args);
+        let result = self.run()?;
+
+        if Rc::ptr_eq(&self.ops, &returned) {
+            self.ops   = ops;
+            self.spans = spans;
+            self.pc    = pc;
+        }
+        Ok(result)
+    }
+
+    /// Executes the single op at `pc`,
+    /// returning whether the fiber is still running,
+    /// paused by a `Yield`, or done.
+    pub fn step(&mut self) -> Result<Status, Effect> {
+        if !self.is_done() {
+            // checked before the op starts, so it never stops halfway
+            match &mut self.fuel {
+                Some(0)    => return Err(Effect::OutOfFuel),
+                Some(left) => *left -= 1,
+                None       => (),
+            }
+
+            if self.trace {
+                let written = writeln!(
+                    self.output.0.borrow_mut(),
+                    "{:04}  {:?}  (depth {})",
+                    self.pc, self.next_op(), self.stack.datum.len(),
+                );
+                #[cfg(feature = "std")]
+                let written = written.map_err(|e| e.kind());
+                written.map_err(Effect::Io)?;
+            }
+
+            #[cfg(feature = "std")]
+            if let Some(counts) = &mut self.counts {
+                let op = &self.ops[self.pc];
+                counts.entry(mem::discriminant(op))
+                    .or_insert_with(|| (op.clone(), 0))
+                    .1 += 1;
+            }
+
+            let yielded = matches!(self.next_op(), Op::Yield);
+            self.execute()?;
+            if yielded {
+                return Ok(Status::Yielded);
+            }
+        }
+
+        // a finished continuation hands its result to its resumer
+        while self.is_done() {
+            let result = self.pop()?;
+            match self.resumer.take() {
+                Some(resumer) => {
+                    self.replace(*resumer);
+                    self.push(result)?;
+                },
+                None => return Ok(Status::Done(result)),
+            }
+        }
+        Ok(Status::Running)
+    }
+
+    fn execute(&mut self) -> Result<(), Effect> {
+        use Op::*;
+
+        // operands are copied out of the op, and the few that aren't
+        // `Copy` are cloned before the fiber changes, so the op itself
+        // is only borrowed.
+        match self.ops[self.pc] {
+            Return(count) => {
+                // the returned values are the top `count` items,
+                // everything else the frame pushed is discarded.
+                // they must all be the frame's own, above its `index`.
+                let floor = self.stack.frames.last()
+                    .map_or(0, |frame| frame.index);
+                let split = self.stack.datum.len().checked_sub(count)
+                    .filter(|&split| split >= floor);
+                let split = self.unwrap_or_fatal(split)?;
+                let popped = self.stack.frames.pop();
+                let mut frame = self.unwrap_or_fatal(popped)?;
+
+                let mut values = self.stack.datum.split_off(split);
+                if values.is_empty() {
+                    values.push(Data::Unit);
+                }
+                self.stack.datum.truncate(frame.index);
+
+                if let Some(clause) = frame.on_return.take() {
+                    let result = if values.len() == 1 {
+                        values.remove(0)
+                    } else {
+                        Data::Tuple(Rc::new(values))
+                    };
+                    // the clause returns to this frame's caller
+                    self.enter(clause, frame.suspend.take());
+                    self.push(result)?;
+                    return Ok(());
+                }
+
+                self.stack.datum.extend(values);
+
+                match frame.suspend.take() {
+                    Some(suspend) => {
+                        self.ops   = suspend.ops;
+                        self.spans = suspend.spans;
+                        self.pc    = suspend.pc;
+                    },
+                    // returning from the base frame ends the fiber
+                    None => {
+                        self.stack.frames.push(frame);
+                        self.kill();
+                    },
+                }
+                return Ok(());
+            },
+
+            Halt => {
+                // the result is left on top for `step` to pop
+                let top = self.stack.datum.last().map(|_| ());
+                self.unwrap_or_fatal(top)?;
+                self.resumer = None;
+                self.kill();
+                return Ok(());
+            },
+
+            // `step` reports the pause once it is past the op
+            Yield => (),
+
+            Const(ref data) => {
+                let data = data.clone();
+                self.push(data)?;
+            },
+
+            ConstF(n) => self.push(Data::Float(n))?,
+
+            ConstI(n) => self.push(Data::Int(n))?,
+
+            ConstIdx(index) => {
+                let data = self.constants.get(index).cloned();
+                let data = self.unwrap_or_fatal(data)?;
+                self.push(data)?;
+            },
+
+            Add => {
+                let (a, b) = self.pop_pair()?;
+                self.push(a.apply_binop(b, BinOp::Add)?)?
+            },
+
+            Sub => {
+                let (a, b) = self.pop_pair()?;
+                self.push(a.apply_binop(b, BinOp::Sub)?)?
+            },
+
+            Mul => {
+                let (a, b) = self.pop_pair()?;
+                self.push(a.apply_binop(b, BinOp::Mul)?)?
+            },
+
+            Div => {
+                let (a, b) = self.pop_pair()?;
+                self.push(a.apply_binop(b, BinOp::Div)?)?
+            },
+
+            Mod => {
+                let (a, b) = self.pop_pair()?;
+                self.push(a.apply_binop(b, BinOp::Mod)?)?
+            },
+
+            Neg => {
+                match self.pop()? {
+                    Data::Float(a) => self.push(Data::Float(-a))?,
+                    Data::Int(a)   => match a.checked_neg() {
+                        Some(n) => self.push(Data::Int(n))?,
+                        None    => Err(Effect::IntegerOverflow)?,
+                    },
+                    other => Err(Effect::mismatch("Float or Int", &other))?,
+                }
+            },
+
+            // comparisons use the same operand order as arithmetic,
+            // so `push a; push b; Lt` computes `a < b`.
+            Eq => {
+                let (a, b) = self.pop_pair()?;
+                self.push(Data::Bool(a == b))?
+            },
+
+            Lt => {
+                let (a, b) = self.pop_pair()?;
+                self.push(Data::try_compare(a, b, Ordering::is_lt)?)?
+            },
+
+            Gt => {
+                let (a, b) = self.pop_pair()?;
+                self.push(Data::try_compare(a, b, Ordering::is_gt)?)?
+            },
+
+            And => {
+                match self.pop_pair()? {
+                    (Data::Bool(a), Data::Bool(b)) => self.push(Data::Bool(a && b))?,
+                    (a, b) => Err(Effect::mismatch_pair(("Bool", "Bool"), (&a, &b)))?,
+                }
+            },
+
+            Or => {
+                match self.pop_pair()? {
+                    (Data::Bool(a), Data::Bool(b)) => self.push(Data::Bool(a || b))?,
+                    (a, b) => Err(Effect::mismatch_pair(("Bool", "Bool"), (&a, &b)))?,
+                }
+            },
+
+            Not => {
+                match self.pop()? {
+                    Data::Bool(a) => self.push(Data::Bool(!a))?,
+                    other => Err(Effect::mismatch("Bool", &other))?,
+                }
+            },
+
+            Assert => self.assert(None)?,
+
+            AssertMsg => {
+                match self.pop()? {
+                    Data::Str(message) => self.assert(Some(message))?,
+                    other => Err(Effect::mismatch("Str", &other))?,
+                }
+            },
+
+            ToBool => {
+                let truthy = self.pop()?.is_truthy();
+                self.push(Data::Bool(truthy))?;
+            },
+
+            Print => {
+                let data = self.pop()?;
+                let written = writeln!(self.output.0.borrow_mut(), "{}", data);
+                #[cfg(feature = "std")]
+                let written = written.map_err(|e| e.kind());
+                written.map_err(Effect::Io)?;
+            },
+
+            IntToFloat => {
+                match self.pop()? {
+                    Data::Int(a) => self.push(Data::Float(a as f64))?,
+                    other => Err(Effect::mismatch("Int", &other))?,
+                }
+            },
+
+            FloatToInt => {
+                match self.pop()? {
+                    Data::Float(a) => self.push(Data::Int(a as i64))?,
+                    other => Err(Effect::mismatch("Float", &other))?,
+                }
+            },
+
+            IsNaN => {
+                match self.pop()? {
+                    Data::Float(a) => self.push(Data::Bool(a.is_nan()))?,
+                    other => Err(Effect::mismatch("Float", &other))?,
+                }
+            },
+
+            IsInf => {
+                match self.pop()? {
+                    Data::Float(a) => self.push(Data::Bool(a.is_infinite()))?,
+                    other => Err(Effect::mismatch("Float", &other))?,
+                }
+            },
+
+            Jump(target) => {
+                self.jump(target)?;
+                return Ok(());
+            },
+
+            JumpIfFalse(target) => {
+                match self.pop()? {
+                    Data::Bool(false) => {
+                        self.jump(target)?;
+                        return Ok(());
+                    },
+                    Data::Bool(true) => (),
+                    other => Err(Effect::mismatch("Bool", &other))?,
+                }
+            },
+
+            Get(Name(index)) => {
+                let data = self.get_slot(index);
+                let data = self.unwrap_or_fatal(data)?;
+                self.push(data)?;
+            },
+
+            Set(Name(index)) => {
+                let index = Some(index).filter(|&index| index < Frame::MAX_SLOTS);
+                let index = self.unwrap_or_fatal(index)?;
+                let data = self.pop()?;
+                match self.stack.frames.last_mut() {
+                    Some(frame) => {
+                        if frame.locals.len() <= index {
+                            frame.locals.resize(index + 1, None);
+                        }
+                        frame.locals[index] = Some(data);
+                    },
+                    None => self.unwrap_or_fatal(None)?,
+                }
+            },
+
+            Handler(name)        => self.install(name, false)?,
+            ShallowHandler(name) => self.install(name, true)?,
+
+            Unhandle(name) => {
+                if let Some(frame) = self.stack.frames.last_mut() {
+                    frame.handlers.remove(&name);
+                }
+            },
+
+            HandleReturn => {
+                let clause = match self.pop()? {
+                    Data::Fun(f) => f,
+                    other => Err(Effect::mismatch("Fun", &other))?,
+                };
+
+                match self.stack.frames.last_mut() {
+                    Some(frame) => frame.on_return = Some(clause),
+                    None => self.unwrap_or_fatal(None)?,
+                }
+            },
+
+            Raise(name) => {
+                self.raise(name, 1)?;
+                return Ok(());
+            }
+
+            TryRaise(name) => {
+                let handled = self.resolve_handler(name).is_some()
+                    || self.hosts.0.borrow().contains_key(&name);
+                if handled {
+                    self.raise(name, 1)?;
+                    return Ok(());
+                }
+                // the argument stays where it is, as the result
+                self.check_args(name, 1)?;
+                let arg = self.pop()?;
+                self.push(arg)?;
+            }
+
+            RaiseN(name, arity) => {
+                self.raise(name, arity)?;
+                return Ok(());
+            }
+
+            Call => {
+                let arg = self.pop()?;
+                let fun = self.pop()?;
+
+                match fun {
+                    Data::Fun(fun) => {
+                        if self.depth() >= self.max_depth {
+                            Err(Effect::StackOverflow)?;
+                        }
+                        self.call(fun);
+                        self.push(arg)?;
+                        return Ok(());
+                    }
+                    Data::Cont(fiber) => {
+                        self.transfer(fiber, arg)?;
+                        return Ok(());
+                    }
+                    Data::Native(native) => {
+                        self.push((native.0)(arg)?)?;
+                    }
+                    other => Err(Effect::mismatch("Fun, Native, or Cont", &other))?,
+                }
+            }
+
+            CallN(n) => {
+                let mut args = self.pop_n(n)?;
+                let fun = self.pop()?;
+
+                match fun {
+                    Data::Fun(fun) => {
+                        if self.depth() >= self.max_depth {
+                            Err(Effect::StackOverflow)?;
+                        }
+                        self.call(fun);
+                        self.extend(args.into_iter())?;
+                        return Ok(());
+                    }
+                    Data::Native(native) => {
+                        let arg = if args.len() == 1 {
+                            args.pop().unwrap()
+                        } else {
+                            Data::Tuple(Rc::new(args))
+                        };
+                        self.push((native.0)(arg)?)?;
+                    }
+                    other => Err(Effect::mismatch("Fun or Native", &other))?,
+                }
+            }
+
+            TailCall => {
+                let arg = self.pop()?;
+                let fun = match self.pop()? {
+                    Data::Fun(fun) => fun,
+                    other => Err(Effect::mismatch("Fun", &other))?,
+                };
+
+                let frame = self.stack.frames.pop();
+                let frame = self.unwrap_or_fatal(frame)?;
+                self.stack.datum.truncate(frame.index);
+                self.enter(fun, frame.suspend);
+
+                let callee = self.stack.frames.last_mut().unwrap();
+                callee.handlers  = frame.handlers;
+                callee.on_return = frame.on_return;
+                self.push(arg)?;
+                return Ok(());
+            }
+
+            Resume => {
+                let data = self.pop()?;
+                let fiber = match self.pop()? {
+                    Data::Cont(fiber) => fiber,
+                    other => Err(Effect::mismatch("Cont", &other))?,
+                };
+
+                self.resume_cont(fiber, data)?;
+                return Ok(());
+            }
+
+            CloneCont => {
+                let fiber = match self.stack.datum.last() {
+                    Some(Data::Cont(fiber)) => fiber,
+                    Some(other) => Err(Effect::mismatch("Cont", other))?,
+                    None    => self.unwrap_or_fatal(None)?,
+                };
+
+                if fiber.resumed.get() {
+                    Err(Effect::ContinuationAlreadyUsed)?;
+                }
+                let copy = Data::Cont(Rc::new((**fiber).clone()));
+                self.push(copy)?;
+            }
+
+            Pop(times) => {
+                // checked up front, so an underflow pops nothing
+                let split = self.stack.datum.len().checked_sub(times);
+                let split = self.unwrap_or_fatal(split)?;
+                self.stack.datum.truncate(split);
+            }
+
+            Drop => {
+                self.pop()?;
+            }
+
+            Dup => {
+                let top = self.stack.datum.last().cloned();
+                let top = self.unwrap_or_fatal(top)?;
+                self.push(top)?;
+            }
+
+            Swap => {
+                let (a, b) = self.pop_pair()?;
+                self.push(b)?;
+                self.push(a)?;
+            }
+
+            Over => {
+                let len = self.stack.datum.len();
+                let second = len.checked_sub(2)
+                    .map(|index| self.stack.datum[index].clone());
+                let second = self.unwrap_or_fatal(second)?;
+                self.push(second)?;
+            }
+
+            Nip => {
+                let second = self.stack.datum.len().checked_sub(2);
+                let second = self.unwrap_or_fatal(second)?;
+                self.stack.datum.remove(second);
+            }
+
+            Rot(n) => {
+                let split = self.stack.datum.len().checked_sub(n);
+                let split = self.unwrap_or_fatal(split)?;
+                if n > 0 { self.stack.datum[split..].rotate_right(1); }
+            }
+
+            Pack(n) => {
+                let items = self.pop_n(n)?;
+                self.push(Data::Tuple(Rc::new(items)))?;
+            }
+
+            Unpack => {
+                match self.pop()? {
+                    Data::Tuple(items) => {
+                        self.extend(items.iter().cloned())?
+                    },
+                    other => Err(Effect::mismatch("Tuple", &other))?,
+                }
+            }
+
+            Concat => {
+                match self.pop_pair()? {
+                    (Data::Str(a), Data::Str(b)) => {
+                        let joined = [&*a, &*b].concat();
+                        self.push(Data::Str(joined.into()))?
+                    },
+                    (a, b) => Err(Effect::mismatch_pair(("Str", "Str"), (&a, &b)))?,
+                }
+            }
+
+            StrRepeat => {
+                let (string, count) = match self.pop_pair()? {
+                    (Data::Str(s), Data::Int(n)) => (s, n),
+                    (a, b) => Err(Effect::mismatch_pair(("Str", "Int"), (&a, &b)))?,
+                };
+                let count = usize::try_from(count)
+                    .map_err(|_| Effect::NegativeCount(count))?;
+
+                let len = string.len().saturating_mul(count);
+                if len > self.max_str_len {
+                    Err(Effect::StrTooLong { len, max: self.max_str_len })?;
+                }
+                self.push(Data::Str(string.repeat(count).into()))?;
+            }
+
+            StrLen => {
+                match self.pop()? {
+                    Data::Str(a) => {
+                        self.push(Data::Int(a.chars().count() as i64))?
+                    },
+                    other => Err(Effect::mismatch("Str", &other))?,
+                }
+            }
+
+            StrIndex => {
+                let (string, index) = match self.pop_pair()? {
+                    (Data::Str(s), Data::Int(i)) => (s, i),
+                    (a, b) => Err(Effect::mismatch_pair(("Str", "Int"), (&a, &b)))?,
+                };
+
+                let c = usize::try_from(index).ok()
+                    .and_then(|i| string.chars().nth(i))
+                    .ok_or_else(|| Effect::IndexOutOfBounds {
+                        index,
+                        len: string.chars().count(),
+                    })?;
+                self.push(Data::Char(c))?;
+            }
+
+            CharToInt => {
+                match self.pop()? {
+                    Data::Char(c) => self.push(Data::Int(c as i64))?,
+                    other => Err(Effect::mismatch("Char", &other))?,
+                }
+            }
+
+            IntToChar => {
+                let n = match self.pop()? {
+                    Data::Int(n) => n,
+                    other => Err(Effect::mismatch("Int", &other))?,
+                };
+                let c = u32::try_from(n).ok()
+                    .and_then(char::from_u32)
+                    .ok_or(Effect::InvalidChar(n))?;
+                self.push(Data::Char(c))?;
+            }
+
+            BytesLen => {
+                match self.pop()? {
+                    Data::Bytes(b) => self.push(Data::Int(b.len() as i64))?,
+                    other => Err(Effect::mismatch("Bytes", &other))?,
+                }
+            }
+
+            BytesGet => {
+                let (bytes, index) = match self.pop_pair()? {
+                    (Data::Bytes(b), Data::Int(i)) => (b, i),
+                    (a, b) => Err(Effect::mismatch_pair(("Bytes", "Int"), (&a, &b)))?,
+                };
+
+                let byte = usize::try_from(index).ok()
+                    .and_then(|i| bytes.get(i))
+                    .ok_or(Effect::IndexOutOfBounds { index, len: bytes.len() })?;
+                self.push(Data::Int(*byte as i64))?;
+            }
+
+            StrToBytes => {
+                match self.pop()? {
+                    Data::Str(s) => self.push(Data::Bytes(s.as_bytes().into()))?,
+                    other => Err(Effect::mismatch("Str", &other))?,
+                }
+            }
+
+            BytesToStr => {
+                let bytes = match self.pop()? {
+                    Data::Bytes(b) => b,
+                    other => Err(Effect::mismatch("Bytes", &other))?,
+                };
+                let string = core::str::from_utf8(&bytes)
+                    .map_err(|e| Effect::InvalidUtf8 { valid_up_to: e.valid_up_to() })?;
+                self.push(Data::Str(string.into()))?;
+            }
+
+            ListNew => {
+                self.push(Data::List(Rc::new(RefCell::new(vec![]))))?;
+            }
+
+            ListPush => {
+                match self.pop_pair()? {
+                    (Data::List(list), item) => {
+                        list.borrow_mut().push(item);
+                        self.push(Data::List(list))?;
+                    },
+                    (other, _) => Err(Effect::mismatch("List", &other))?,
+                }
+            }
+
+            ListGet => {
+                let (list, index) = match self.pop_pair()? {
+                    (Data::List(l), Data::Int(i)) => (l, i),
+                    (a, b) => Err(Effect::mismatch_pair(("List", "Int"), (&a, &b)))?,
+                };
+
+                let list = list.borrow();
+                let item = usize::try_from(index).ok()
+                    .and_then(|i| list.get(i))
+                    .cloned()
+                    .ok_or(Effect::IndexOutOfBounds { index, len: list.len() })?;
+                self.push(item)?;
+            }
+
+            ListLen => {
+                match self.pop()? {
+                    Data::List(list) => {
+                        let len = list.borrow().len();
+                        self.push(Data::Int(len as i64))?;
+                    },
+                    other => Err(Effect::mismatch("List", &other))?,
+                }
+            }
+
+            MapNew => {
+                self.push(Data::Map(Rc::new(RefCell::new(BTreeMap::new()))))?;
+            }
+
+            MapSet => {
+                let value = self.pop()?;
+                let (map, key) = match self.pop_pair()? {
+                    (Data::Map(m), key) => (m, Key::try_from(key)?),
+                    (other, _) => Err(Effect::mismatch("Map", &other))?,
+                };
+
+                map.borrow_mut().insert(key, value);
+                self.push(Data::Map(map))?;
+            }
+
+            MapGet => {
+                let (map, key) = match self.pop_pair()? {
+                    (Data::Map(m), key) => (m, Key::try_from(key)?),
+                    (other, _) => Err(Effect::mismatch("Map", &other))?,
+                };
+
+                let value = map.borrow().get(&key).cloned()
+                    .ok_or(Effect::MissingKey(key))?;
+                self.push(value)?;
+            }
+
+            Capture => {
+                let raw_fun = match self.pop()? {
+                    Data::RawFun(r) => r,
+                    other => Err(Effect::mismatch("RawFun", &other))?,
+                };
+                self.capture(raw_fun)?;
+            }
+
+            Closure(ref raw_fun) => {
+                let raw_fun = raw_fun.clone();
+                self.capture(raw_fun)?
+            },
+        }
+
+        self.pc += 1;
+        Ok(())
+    }
+
+    /// Takes the fiber out of a continuation so it can be resumed.
+    /// Continuations are one-shot: every copy of a `Data::Cont`
+    /// refers to the same continuation, and once any of them
+    /// has been resumed, resuming another is an error.
+    /// To resume more than once, make an independent
+    /// continuation for each resumption with `CloneCont`.
+    fn resumable(cont: Rc<Fiber>) -> Result<Fiber, Effect> {
+        if cont.resumed.replace(true) {
+            return Err(Effect::ContinuationAlreadyUsed);
+        }
+
+        let fiber = Rc::try_unwrap(cont).unwrap_or_else(|shared| (*shared).clone());
+        fiber.resumed.set(false);
+        Ok(fiber)
+    }
+
+    /// Continues `cont` with `data` as the result of the op that
+    /// suspended it. This fiber carries on at the next op
+    /// once `cont` finishes, with its result on top.
+    fn resume_cont(&mut self, cont: Rc<Fiber>, data: Data) -> Result<(), Effect> {
+        // this fiber waits on the continuation, like a caller on a call
+        if self.depth() >= self.max_depth {
+            return Err(Effect::StackOverflow);
+        }
+        if let Some((name, signature)) = &cont.expects {
+            signature.check_resume(*name, &data)?;
+        }
+        // the continuation gets this fiber's limits when it takes over
+        if cont.stack.datum.len() >= self.max_stack {
+            return Err(Effect::DatumOverflow);
+        }
+        // effects raised after resuming go where the continuation's would,
+        // if it handled them, as it is no longer there to handle them.
+        let from_cont = self.parent.as_ref()
+            .is_some_and(|parent| Rc::ptr_eq(parent, &cont));
+        if from_cont {
+            // so the continuation isn't shared, and needn't be copied
+            self.parent = None;
+        }
+        let parent = cont.parent.clone();
+        let parent_frames = cont.parent_frames;
+        let mut cont = Fiber::resumable(cont)?;
+        cont.expects = None;
+
+        // the continuation already points past its `Raise`
+        self.pc += 1;
+        let mut resumer = self.replace(cont);
+        if from_cont {
+            resumer.parent = parent;
+            resumer.parent_frames = parent_frames;
+        }
+        self.resumer = Some(Box::new(resumer));
+        self.push(data)?;
+        Ok(())
+    }
+
+    /// Continues `cont` with `data` as the result of the op that
+    /// suspended it, in place of this fiber, which is dropped.
+    /// Whatever this fiber would have handed its result to once finished,
+    /// `cont` hands its result to instead.
+    fn transfer(&mut self, cont: Rc<Fiber>, data: Data) -> Result<(), Effect> {
+        if let Some((name, signature)) = &cont.expects {
+            signature.check_resume(*name, &data)?;
+        }
+        if cont.stack.datum.len() >= self.max_stack {
+            return Err(Effect::DatumOverflow);
+        }
+        if self.parent.as_ref().is_some_and(|parent| Rc::ptr_eq(parent, &cont)) {
+            self.parent = None;
+        }
+        let mut cont = Fiber::resumable(cont)?;
+        cont.expects = None;
+
+        let resumer = self.resumer.take();
+        self.replace(cont);
+        self.resumer = resumer;
+        self.push(data)?;
+        Ok(())
+    }
+
+    /// Makes `fiber` the active fiber, returning the old one.
+    /// Host-side settings, like tracing, stay with the active fiber.
+    fn replace(&mut self, mut fiber: Fiber) -> Fiber {
+        fiber.trace     = self.trace;
+        fiber.max_depth = self.max_depth;
+        fiber.max_stack = self.max_stack;
+        fiber.max_str_len = self.max_str_len;
+        fiber.fuel      = self.fuel;
+        fiber.hosts     = self.hosts.clone();
+        fiber.signatures = self.signatures.clone();
+        fiber.constants = self.constants.clone();
+        fiber.output    = self.output.clone();
+        fiber.symbols   = self.symbols.clone();
+        fiber.entry     = self.entry.clone();
+        #[cfg(feature = "std")]
+        {
+            fiber.counts = self.counts.take();
+        }
+        mem::replace(self, fiber)
+    }
+
+    /// Moves the fibers this one owns, its resumer, and its parent
+    /// and the continuations on its stack if no one else holds them,
+    /// into `chain`, for `drop` to drop one at a time.
+    fn unlink(&mut self, chain: &mut Vec<Fiber>) {
+        if let Some(resumer) = self.resumer.take() {
+            chain.push(*resumer);
+        }
+        if let Some(parent) = self.parent.take() {
+            if let Ok(parent) = Rc::try_unwrap(parent) {
+                chain.push(parent);
+            }
+        }
+
+        let locals = self.stack.frames.iter_mut()
+            .flat_map(|frame| frame.locals.iter_mut().flatten());
+        for data in self.stack.datum.iter_mut().chain(locals) {
+            if let Data::Cont(_) = data {
+                if let Data::Cont(cont) = mem::replace(data, Data::Unit) {
+                    if let Ok(cont) = Rc::try_unwrap(cont) {
+                        chain.push(cont);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Pops a `Bool`, failing with `message` if it is `false`.
+    fn assert(&mut self, message: Option<Rc<str>>) -> Result<(), Effect> {
+        match self.pop()? {
+            Data::Bool(true) => Ok(()),
+            Data::Bool(false) => Err(Effect::AssertionFailed { pc: self.pc, message }),
+            other => Err(Effect::mismatch("Bool", &other)),
+        }
+    }
+
+    /// Pops the captures of `raw_fun` and pushes the `Fun` they make.
+    fn capture(&mut self, raw_fun: RawFun) -> Result<(), Effect> {
+        // the first value pushed becomes capture 0
+        let captures = self.pop_n(raw_fun.num_captures)?;
+
+        self.push(Data::Fun(Fun {
+            ops:      raw_fun.ops,
+            captures: Rc::new(captures),
+            spans:    raw_fun.spans,
+        }))
+    }
+
+    /// Pops a `Fun` and installs it as a handler for `name`.
+    fn install(&mut self, name: Name, shallow: bool) -> Result<(), Effect> {
+        let installed = self.stack.frames.last()
+            .and_then(|frame| frame.handlers.get(&name))
+            .is_some_and(|handler| !handler.fired.get());
+        if installed { return Err(Effect::DuplicateHandler(name)); }
+
+        let fp = match self.pop()? {
+            Data::Fun(f) => f,
+            other => Err(Effect::mismatch("Fun", &other))?,
+        };
+
+        match self.stack.frames.last_mut() {
+            Some(frame) => {
+                let handler = EffectHandler { fun: fp, shallow, fired: Cell::new(false) };
+                frame.handlers.insert(name, handler);
+                Ok(())
+            },
+            None => self.unwrap_or_fatal(None),
+        }
+    }
+
+    /// Raises effect `name`, passing the top `arity` values
+    /// to the nearest handler installed for it.
+    /// The handler runs in a new fiber, whose stack starts with
+    /// the continuation of this fiber followed by the arguments,
+    /// in the order they were pushed.
+    /// Effects the handler raises are handled by the handlers
+    /// in the frames below the one that installed it,
+    /// so a deep handler doesn't catch its own raises;
+    /// they go on to the handlers further out.
+    ///
+    /// A shallow handler is uninstalled before it runs;
+    /// one found in a parent fiber, which is shared and can't be changed,
+    /// is marked as fired instead, which comes to the same.
+    fn raise(&mut self, name: Name, arity: usize) -> Result<(), Effect> {
+        // the handler runs in a frame of its own, like a call
+        if self.depth() >= self.max_depth {
+            return Err(Effect::StackOverflow);
+        }
+        let signature = self.check_args(name, arity)?;
+        let resolved = self.resolve_handler(name);
+
+        let args = self.pop_n(arity)?;
+        let Resolved { handler, fiber, frame } = match resolved {
+            Some(resolved) => resolved,
+            None => return self.raise_host(name, args, signature),
+        };
+
+        if handler.shallow {
+            match &fiber {
+                Some(fiber) => fiber.stack.frames[frame].handlers[&name].fired.set(true),
+                None => { self.stack.frames[frame].handlers.remove(&name); },
+            }
+        }
+        // the continuation checks what it's resumed with
+        self.expects = signature.map(|signature| (name, signature));
+        self.switch(Fiber::new(handler.fun), args);
+        if let Some(fiber) = fiber {
+            self.parent = Some(fiber);
+        }
+        self.parent_frames = frame;
+        Ok(())
+    }
+
+    /// Checks the top `arity` values against the signature of `name`,
+    /// returning the signature, if there is one.
+    fn check_args(
+        &self,
+        name: Name,
+        arity: usize,
+    ) -> Result<Option<Signature>, Effect> {
+        let signature = match self.signatures.get(&name) {
+            Some(signature) => signature,
+            None => return Ok(None),
+        };
+        if signature.args.len() != arity {
+            return Err(Effect::EffectArityMismatch {
+                name,
+                expected: signature.args.len(),
+                found:    arity,
+            });
+        }
+
+        // too few values is left for popping them to report
+        let start = self.stack.datum.len().saturating_sub(arity);
+        let args = &self.stack.datum[start..];
+        for (expected, data) in signature.args.iter().zip(args) {
+            if *expected != data.type_name() {
+                return Err(Effect::EffectTypeMismatch {
+                    name,
+                    expected,
+                    found: data.type_name(),
+                });
+            }
+        }
+        Ok(Some(signature.clone()))
+    }
+
+    /// Handles effect `name` with its host callback, if there is one,
+    /// otherwise it escapes to the host as `Effect::Virtual`,
+    /// leaving the fiber paused at the raise without its arguments.
+    fn raise_host(
+        &mut self,
+        name: Name,
+        mut args: Vec<Data>,
+        signature: Option<Signature>,
+    ) -> Result<(), Effect> {
+        let hosts = self.hosts.clone();
+        let mut hosts = hosts.0.borrow_mut();
+        let host = match hosts.get_mut(&name) {
+            Some(h) => h,
+            None => {
+                let symbol = self.symbols.name_of(name).map(Rc::from);
+                return Err(Effect::Virtual { name, symbol, args });
+            },
+        };
+
+        let arg = if args.len() == 1 {
+            args.pop().unwrap()
+        } else {
+            Data::Tuple(Rc::new(args))
+        };
+        let result = host(arg)?;
+        if let Some(signature) = signature {
+            signature.check_resume(name, &result)?;
+        }
+        self.push(result)?;
+        self.pc += 1;
+        Ok(())
+    }
+
+    /// Suspends this fiber and makes `other_fiber` the active one,
+    /// pushing the continuation of this fiber followed by `args`.
+    /// The continuation picks up at the op after the current one,
+    /// so the op that switched isn't run again on resumption.
+    /// This fiber becomes the parent of `other_fiber`,
+    /// so effects `other_fiber` raises are handled as if raised here,
+    /// even once it has dropped the continuation.
+    /// Whatever this fiber would have handed its result to once finished,
+    /// `other_fiber` hands its result to instead.
+    pub fn switch(&mut self, other_fiber: Fiber, args: Vec<Data>) {
+        self.pc += 1;
+        let resumer = self.resumer.take();
+        let old_fiber = Rc::new(self.replace(other_fiber));
+        self.resumer = resumer;
+        self.parent = Some(old_fiber.clone());
+        self.parent_frames = usize::MAX;
+        // the handler starts with these alone, so they aren't limited
+        self.stack.datum.push(Data::Cont(old_fiber));
+        self.stack.datum.extend(args);
+    }
+
+    /// Enters `fun` in a new frame; when it returns,
+    /// execution resumes at the op after the current one.
+    pub fn call(&mut self, fun: Fun) {
+        let suspend = Suspend::new(
+            self.ops.clone(),
+            self.spans.clone(),
+            self.pc + 1,
+        );
+        self.enter(fun, Some(suspend));
+    }
+
+    /// Enters `fun` in a new frame that returns to `suspend`.
+    fn enter(&mut self, fun: Fun, suspend: Option<Suspend>) {
+        self.ops   = fun.ops;
+        self.spans = fun.spans;
+        self.pc    = 0;
+
+        let frame = Frame::new(
+            suspend,
+            self.stack.datum.len(),
+            fun.captures,
+        );
+        self.stack.frames.push(frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    fn fun(ops: Vec<Op>) -> Fun {
+        Fun { ops: Rc::new(ops), captures: Rc::new(vec![]), spans: None }
+    }
+
+    fn run(src: &str) -> Result<Data, Effect> {
+        Fiber::new(assemble(src).unwrap()).run()
+    }
+
+    fn float(n: f64) -> Op {
+        Op::Const(Data::Float(n))
+    }
+
+    /// What a test's output is written to, as a `Sink`.
+    #[cfg(feature = "std")]
+    type Buffer = Vec<u8>;
+    #[cfg(not(feature = "std"))]
+    type Buffer = String;
+
+    /// A buffer to hand to `with_output`, and the sink that writes to it.
+    fn buffer() -> (Rc<RefCell<Buffer>>, Rc<RefCell<Sink>>) {
+        let buffer = Rc::new(RefCell::new(Buffer::new()));
+        (buffer.clone(), buffer)
+    }
+
+    fn contents(buffer: &RefCell<Buffer>) -> String {
+        #[cfg(feature = "std")]
+        return String::from_utf8(buffer.borrow().clone()).unwrap();
+        #[cfg(not(feature = "std"))]
+        return buffer.borrow().clone();
+    }
+
+    fn int(n: i64) -> Op {
+        Op::Const(Data::Int(n))
+    }
+
+    fn string(s: &str) -> Op {
+        Op::Const(Data::Str(s.into()))
+    }
+
+    fn floats(fiber: &Fiber) -> Vec<f64> {
+        fiber.stack.datum.iter().map(|data| match data {
+            Data::Float(n) => *n,
+            other => panic!("expected a Float, got {:?}", other),
+        }).collect()
+    }
+
+    fn as_float(data: Data) -> f64 {
+        match data {
+            Data::Float(n) => n,
+            other => panic!("expected a Float, got {:?}", other),
+        }
+    }
+
+    fn run_float(ops: Vec<Op>) -> f64 {
+        as_float(Fiber::new(fun(ops)).run().unwrap())
+    }
+
+    fn run_int(ops: Vec<Op>) -> i64 {
+        match Fiber::new(fun(ops)).run().unwrap() {
+            Data::Int(n) => n,
+            other => panic!("expected an Int, got {:?}", other),
+        }
+    }
+
+    fn run_bool(ops: Vec<Op>) -> bool {
+        match Fiber::new(fun(ops)).run().unwrap() {
+            Data::Bool(b) => b,
+            other => panic!("expected a Bool, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn return_leaves_its_values_on_the_callers_stack() {
+        let callee = fun(vec![
+            Op::Pop(1),
+            Op::Const(Data::Float(2.0)),
+            Op::Const(Data::Float(3.0)),
+            Op::Const(Data::Float(4.0)),
+            Op::Return(2),
+        ]);
+        let mut fiber = Fiber::new(fun(vec![
+            Op::Const(Data::Float(1.0)),
+            Op::Const(Data::Fun(callee)),
+            Op::Const(Data::Float(0.0)),
+            Op::Call,
+        ]));
+        assert_eq!(as_float(fiber.run().unwrap()), 4.0);
+        assert_eq!(floats(&fiber), [1.0, 3.0]);
+        assert_eq!(fiber.stack.frames.len(), 1);
+    }
+
+    #[test]
+    fn return_from_the_base_frame_ends_the_fiber() {
+        let mut fiber = Fiber::new(fun(vec![
+            Op::Const(Data::Float(1.0)),
+            Op::Const(Data::Float(2.0)),
+            Op::Return(1),
+            Op::Const(Data::Float(3.0)),
+        ]));
+        assert_eq!(as_float(fiber.run().unwrap()), 2.0);
+        assert!(fiber.is_done());
+    }
+
+    #[test]
+    fn get_reads_the_frames_captures() {
+        let callee = Fun {
+            ops:      Rc::new(vec![Op::Pop(1), Op::Get(Name(1)), Op::Return(1)]),
+            captures: Rc::new(vec![Data::Float(1.5), Data::Float(2.5)]),
+            spans:    None,
+        };
+        let mut fiber = Fiber::new(fun(vec![
+            Op::Const(Data::Fun(callee)),
+            Op::Const(Data::Float(0.0)),
+            Op::Call,
+        ]));
+        assert_eq!(as_float(fiber.run().unwrap()), 2.5);
+    }
+
+    #[test]
+    fn get_out_of_bounds_is_fatal() {
+        let mut fiber = Fiber::new(fun(vec![Op::Get(Name(3))]));
+        assert!(matches!(fiber.run(), Err(Effect::Fatal { pc: 0, .. })));
+    }
+
+    #[test]
+    fn set_then_get_a_slot() {
+        let mut fiber = Fiber::new(fun(vec![
+            Op::Const(Data::Float(4.0)),
+            Op::Set(Name(0)),
+            Op::Const(Data::Float(5.0)),
+            Op::Set(Name(2)),
+            Op::Get(Name(0)),
+            Op::Get(Name(2)),
+            Op::Add,
+        ]));
+        assert_eq!(as_float(fiber.run().unwrap()), 9.0);
+    }
+
+    #[test]
+    fn setting_a_slot_past_the_last_is_fatal() {
+        for slot in [Frame::MAX_SLOTS, 1 << 40, usize::MAX] {
+            let ops = vec![Op::Const(Data::Float(1.0)), Op::Set(Name(slot))];
+            assert!(matches!(Fiber::new(fun(ops)).run(), Err(Effect::Fatal { pc: 1, .. })));
+        }
+        let last = Frame::MAX_SLOTS - 1;
+        let ops = vec![Op::Const(Data::Float(1.0)), Op::Set(Name(last)), Op::Get(Name(last))];
+        let mut fiber = Fiber::new(fun(ops));
+        assert_eq!(as_float(fiber.run().unwrap()), 1.0);
+    }
+
+    #[test]
+    fn set_slot_shadows_the_capture_with_its_index() {
+        let callee = Fun {
+            ops: Rc::new(vec![
+                Op::Pop(1),
+                Op::Get(Name(0)),
+                Op::Const(Data::Float(7.0)),
+                Op::Set(Name(0)),
+                Op::Get(Name(0)),
+                Op::Add,
+                Op::Return(1),
+            ]),
+            captures: Rc::new(vec![Data::Float(1.0)]),
+            spans:    None,
+        };
+        let mut fiber = Fiber::new(fun(vec![
+            Op::Const(Data::Fun(callee)),
+            Op::Const(Data::Float(0.0)),
+            Op::Call,
+        ]));
+        assert_eq!(as_float(fiber.run().unwrap()), 8.0);
+    }
+
+    #[test]
+    fn capture_takes_values_in_the_order_pushed() {
+        let raw_fun = RawFun { ops: Rc::new(vec![]), num_captures: 2, spans: None };
+        let mut fiber = Fiber::new(fun(vec![
+            Op::Const(Data::Float(1.0)),
+            Op::Const(Data::Float(2.0)),
+            Op::Const(Data::RawFun(raw_fun)),
+            Op::Capture,
+        ]));
+        match fiber.run().unwrap() {
+            Data::Fun(fun) => match fun.captures.as_slice() {
+                [Data::Float(a), Data::Float(b)] => assert_eq!((*a, *b), (1.0, 2.0)),
+                other => panic!("expected two floats, got {:?}", other),
+            },
+            other => panic!("expected a fun, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn capture_more_than_the_stack_holds_is_fatal() {
+        let raw_fun = RawFun { ops: Rc::new(vec![]), num_captures: 2, spans: None };
+        let mut fiber = Fiber::new(fun(vec![
+            Op::Const(Data::Float(1.0)),
+            Op::Const(Data::RawFun(raw_fun)),
+            Op::Capture,
+        ]));
+        assert!(matches!(fiber.run(), Err(Effect::Fatal { pc: 2, .. })));
+        assert_eq!(floats(&fiber), [1.0]);
+    }
+
+    #[test]
+    fn second_from_top_is_the_left_operand() {
+        assert_eq!(run_float(vec![float(6.0), float(2.0), Op::Div]), 3.0);
+        assert_eq!(run_float(vec![float(6.0), float(2.0), Op::Add]), 8.0);
+    }
+
+    #[test]
+    fn dividing_by_zero_raises() {
+        let ops = vec![Op::Const(Data::Float(4.0)), Op::Const(Data::Float(0.0)), Op::Div];
+        assert!(matches!(Fiber::new(fun(ops)).run(), Err(Effect::ZeroDivision)));
+    }
+
+    #[test]
+    fn dividing_zero_is_zero() {
+        let ops = vec![Op::Const(Data::Float(0.0)), Op::Const(Data::Float(4.0)), Op::Div];
+        assert_eq!(run_float(ops), 0.0);
+    }
+
+    #[test]
+    fn resume_substitutes_the_result_of_the_raise() {
+        // the handler ignores the argument and resumes with 42
+        let handler = fun(vec![
+            Op::Pop(1),
+            Op::Const(Data::Float(42.0)),
+            Op::Resume,
+            Op::Return(1),
+        ]);
+        assert_eq!(run_float(vec![
+            Op::Const(Data::Fun(handler)),
+            Op::Handler(Name(0)),
+            Op::Const(Data::Float(1.0)),
+            Op::Raise(Name(0)),
+            Op::Const(Data::Float(1.0)),
+            Op::Add,
+        ]), 43.0);
+    }
+
+    #[test]
+    fn resume_takes_the_value_on_top_and_the_continuation_below() {
+        let handler = fun(vec![
+            Op::Set(Name(0)),
+            Op::Set(Name(1)),
+            Op::Get(Name(0)),
+            Op::Get(Name(1)),
+            Op::Resume,
+            Op::Return(1),
+        ]);
+        let mut fiber = Fiber::new(fun(vec![
+            Op::Const(Data::Fun(handler)),
+            Op::Handler(Name(0)),
+            Op::Const(Data::Float(1.0)),
+            Op::Raise(Name(0)),
+        ]));
+        assert!(matches!(fiber.run(), Err(Effect::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn tracing_is_off_unless_asked_for() {
+        let ops = vec![Op::Const(Data::Float(1.0)), Op::Const(Data::Float(2.0)), Op::Add];
+        assert!(!Fiber::new(fun(ops.clone())).trace);
+
+        // tracing only writes output, so the result is the same
+        let traced = Fiber::new(fun(ops)).with_trace(true).run().unwrap();
+        assert_eq!(as_float(traced), 3.0);
+    }
+
+    /// The program in `main.rs`.
+    fn demo() -> Fun {
+        fun(vec![
+            Op::Const(Data::Float(3.0)),
+            Op::Const(Data::Float(4.0)),
+            Op::Const(Data::Float(5.0)),
+            Op::Add,
+            Op::Div,
+            Op::Const(Data::Fun(fun(vec![Op::Call]))),
+            Op::Handler(Name(0)),
+            Op::Raise(Name(0)),
+        ])
+    }
+
+    #[test]
+    fn step_runs_one_op_at_a_time() {
+        let mut fiber = Fiber::new(demo());
+
+        for expected in [vec![3.0], vec![3.0, 4.0], vec![3.0, 4.0, 5.0], vec![3.0, 9.0]] {
+            assert_eq!(fiber.step().unwrap(), Status::Running);
+            assert_eq!(floats(&fiber), expected);
+        }
+        assert_eq!(fiber.step().unwrap(), Status::Running);
+        assert_eq!(fiber.pc, 5);
+        assert_eq!(floats(&fiber), [3.0 / 9.0]);
+
+        let mut status = Status::Running;
+        while status == Status::Running {
+            status = fiber.step().unwrap();
+        }
+        assert_eq!(status, Status::Done(Data::Float(3.0 / 9.0)));
+    }
+
+    #[test]
+    fn subtracting_floats() {
+        assert_eq!(run_float(vec![float(10.0), float(3.0), Op::Sub]), 7.0);
+    }
+
+    #[test]
+    fn multiplying_floats() {
+        assert_eq!(run_float(vec![float(6.0), float(7.0), Op::Mul]), 42.0);
+    }
+
+    #[test]
+    fn multiplying_a_fun_is_a_type_mismatch() {
+        let not_a_number = || Op::Const(Data::Fun(fun(vec![])));
+        let mut fiber = Fiber::new(fun(vec![float(6.0), not_a_number(), Op::Mul]));
+        assert!(matches!(fiber.run(), Err(Effect::TypeMismatch { .. })));
+        let mut fiber = Fiber::new(fun(vec![not_a_number(), float(6.0), Op::Mul]));
+        assert!(matches!(fiber.run(), Err(Effect::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn modulo_of_floats() {
+        assert_eq!(run_float(vec![float(7.0), float(3.0), Op::Mod]), 1.0);
+    }
+
+    #[test]
+    fn modulo_by_zero_raises() {
+        let mut fiber = Fiber::new(fun(vec![float(5.0), float(0.0), Op::Mod]));
+        assert!(matches!(fiber.run(), Err(Effect::ZeroDivision)));
+    }
+
+    #[test]
+    fn negating_a_float() {
+        assert_eq!(run_float(vec![float(3.0), Op::Neg]), -3.0);
+    }
+
+    #[test]
+    fn negating_negative_zero_is_positive_zero() {
+        let n = run_float(vec![float(-0.0), Op::Neg]);
+        assert!(n == 0.0 && n.is_sign_positive());
+    }
+
+    #[test]
+    fn negating_a_fun_is_a_type_mismatch() {
+        let mut fiber = Fiber::new(fun(vec![Op::Const(Data::Fun(fun(vec![]))), Op::Neg]));
+        assert!(matches!(fiber.run(), Err(Effect::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn comparing_floats() {
+        let compare = |a, b, op| run_bool(vec![float(a), float(b), op]);
+        assert!(compare(1.0, 2.0, Op::Lt));
+        assert!(!compare(2.0, 1.0, Op::Lt));
+        assert!(compare(2.0, 1.0, Op::Gt));
+        assert!(!compare(1.0, 2.0, Op::Gt));
+        assert!(compare(2.0, 2.0, Op::Eq));
+        assert!(!compare(2.0, 1.0, Op::Eq));
+    }
+
+    #[test]
+    fn comparing_bools() {
+        let bool = |b| Op::Const(Data::Bool(b));
+        assert!(run_bool(vec![bool(true), bool(true), Op::Eq]));
+        assert!(!run_bool(vec![bool(true), bool(false), Op::Eq]));
+    }
+
+    fn abs(x: f64) -> f64 {
+        run_float(vec![
+            float(x),
+            Op::Set(Name(0)),
+            Op::Get(Name(0)),
+            float(0.0),
+            Op::Lt,
+            Op::JumpIfFalse(9),
+            Op::Get(Name(0)),
+            Op::Neg,
+            Op::Set(Name(0)),
+            Op::Get(Name(0)),
+        ])
+    }
+
+    #[test]
+    fn abs_with_a_conditional_jump() {
+        assert_eq!(abs(-2.5), 2.5);
+        assert_eq!(abs(2.5), 2.5);
+    }
+
+    #[test]
+    fn jumping_past_the_end_is_fatal() {
+        let mut fiber = Fiber::new(fun(vec![Op::Jump(5)]));
+        assert!(matches!(fiber.run(), Err(Effect::Fatal { pc: 0, .. })));
+        let mut fiber = Fiber::new(fun(vec![Op::Const(Data::Bool(false)), Op::JumpIfFalse(9)]));
+        assert!(matches!(fiber.run(), Err(Effect::Fatal { pc: 1, .. })));
+    }
+
+    #[test]
+    fn jumping_to_the_end_finishes() {
+        assert_eq!(run_float(vec![float(1.0), Op::Jump(3), float(2.0)]), 1.0);
+    }
+
+    /// Runs `ops` to the end, leaving the stack as the last op left it.
+    fn stack_after(ops: Vec<Op>) -> Result<Vec<f64>, Effect> {
+        let mut fiber = Fiber::new(fun(ops));
+        while !fiber.is_done() {
+            fiber.execute()?;
+        }
+        Ok(floats(&fiber))
+    }
+
+    #[test]
+    fn dup_swap_and_drop_on_two_values() {
+        let two = |op| vec![float(1.0), float(2.0), op];
+        assert_eq!(stack_after(two(Op::Dup)).unwrap(), [1.0, 2.0, 2.0]);
+        assert_eq!(stack_after(two(Op::Swap)).unwrap(), [2.0, 1.0]);
+        assert_eq!(stack_after(two(Op::Drop)).unwrap(), [1.0]);
+    }
+
+    #[test]
+    fn stack_ops_on_too_few_values_are_fatal() {
+        assert!(matches!(stack_after(vec![Op::Dup]), Err(Effect::Fatal { pc: 0, .. })));
+        assert!(matches!(stack_after(vec![float(1.0), Op::Swap]), Err(Effect::Fatal { pc: 1, .. })));
+        assert!(matches!(stack_after(vec![Op::Drop]), Err(Effect::Fatal { pc: 0, .. })));
+    }
+
+    #[test]
+    fn int_arithmetic() {
+        assert_eq!(run_int(vec![int(7), int(3), Op::Add]), 10);
+        assert_eq!(run_int(vec![int(7), int(3), Op::Sub]), 4);
+        assert_eq!(run_int(vec![int(7), int(3), Op::Mul]), 21);
+        assert_eq!(run_int(vec![int(7), int(3), Op::Div]), 2);
+        assert_eq!(run_int(vec![int(-7), int(2), Op::Div]), -3);
+    }
+
+    #[test]
+    fn int_division_by_zero_raises() {
+        let mut fiber = Fiber::new(fun(vec![int(7), int(0), Op::Div]));
+        assert!(matches!(fiber.run(), Err(Effect::ZeroDivision)));
+    }
+
+    #[test]
+    fn mixed_int_and_float_is_a_type_mismatch() {
+        let mut fiber = Fiber::new(fun(vec![int(1), float(1.0), Op::Add]));
+        assert!(matches!(fiber.run(), Err(Effect::TypeMismatch { .. })));
+        let mut fiber = Fiber::new(fun(vec![float(1.0), int(1), Op::Add]));
+        assert!(matches!(fiber.run(), Err(Effect::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn converting_between_int_and_float() {
+        assert_eq!(run_float(vec![int(3), Op::IntToFloat]), 3.0);
+        assert_eq!(run_int(vec![float(3.7), Op::FloatToInt]), 3);
+        let mut fiber = Fiber::new(fun(vec![int(3), Op::FloatToInt]));
+        assert!(matches!(fiber.run(), Err(Effect::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn function_returns_unit() {
+        let callee = fun(vec![Op::Pop(1), Op::Return(0)]);
+        let mut fiber = Fiber::new(fun(vec![Op::Const(Data::Fun(callee)), int(1), Op::Call]));
+        assert!(matches!(fiber.run(), Ok(Data::Unit)));
+    }
+
+    #[test]
+    fn unit_equals_unit() {
+        assert!(run_bool(vec![Op::Const(Data::Unit), Op::Const(Data::Unit), Op::Eq]));
+    }
+
+    #[test]
+    fn handler_sums_a_two_argument_effect() {
+        // the handler gets the continuation, then the arguments in order
+        let raise = |a, b, op| {
+            let handler = fun(vec![op, Op::Resume, Op::Return(1)]);
+            run_int(vec![
+                Op::Const(Data::Fun(handler)),
+                Op::Handler(Name(0)),
+                int(a),
+                int(b),
+                Op::RaiseN(Name(0), 2),
+            ])
+        };
+        assert_eq!(raise(3, 4, Op::Add), 7);
+        assert_eq!(raise(10, 4, Op::Sub), 6);
+    }
+
+    #[test]
+    fn tuple_round_trips_through_pack_and_unpack() {
+        let values = vec![int(1), Op::Const(Data::Bool(true)), float(2.5)];
+        let items = "[Int(1), Bool(true), Float(2.5)]";
+        match Fiber::new(fun([values.clone(), vec![Op::Pack(3)]].concat())).run().unwrap() {
+            Data::Tuple(tuple) => assert_eq!(format!("{:?}", tuple), items),
+            other => panic!("expected a tuple, got {:?}", other),
+        }
+
+        let mut fiber = Fiber::new(fun([values, vec![Op::Pack(3), Op::Unpack]].concat()));
+        while !fiber.is_done() {
+            fiber.execute().unwrap();
+        }
+        assert_eq!(format!("{:?}", fiber.stack.datum), items);
+    }
+
+    #[test]
+    fn unpack_of_a_non_tuple_is_a_type_mismatch() {
+        let mut fiber = Fiber::new(fun(vec![int(1), Op::Unpack]));
+        assert!(matches!(fiber.run(), Err(Effect::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn pack_more_than_the_stack_holds_is_fatal() {
+        let mut fiber = Fiber::new(fun(vec![int(1), Op::Pack(2)]));
+        assert!(matches!(fiber.run(), Err(Effect::Fatal { pc: 1, .. })));
+    }
+
+    #[test]
+    fn concatenating_strings() {
+        let concat = |a: &str, b: &str| {
+            let ops = vec![string(a), string(b), Op::Concat];
+            match Fiber::new(fun(ops)).run().unwrap() {
+                Data::Str(joined) => joined,
+                other => panic!("expected a Str, got {:?}", other),
+            }
+        };
+        assert_eq!(&*concat("ab", "cd"), "abcd");
+        assert_eq!(&*concat("", "cd"), "cd");
+    }
+
+    #[test]
+    fn length_of_a_string() {
+        assert_eq!(run_int(vec![string("abc"), Op::StrLen]), 3);
+        assert_eq!(run_int(vec![string(""), Op::StrLen]), 0);
+    }
+
+    #[test]
+    fn concatenating_a_non_string_is_a_type_mismatch() {
+        let mut fiber = Fiber::new(fun(vec![string("ab"), int(1), Op::Concat]));
+        assert!(matches!(fiber.run(), Err(Effect::TypeMismatch { .. })));
+        let mut fiber = Fiber::new(fun(vec![int(1), Op::StrLen]));
+        assert!(matches!(fiber.run(), Err(Effect::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn building_and_reading_a_list() {
+        let list = || vec![Op::ListNew, int(5), Op::ListPush, int(6), Op::ListPush];
+        assert_eq!(run_int([list(), vec![int(0), Op::ListGet]].concat()), 5);
+        assert_eq!(run_int([list(), vec![int(1), Op::ListGet]].concat()), 6);
+        assert_eq!(run_int([list(), vec![Op::ListLen]].concat()), 2);
+    }
+
+    #[test]
+    fn list_get_out_of_bounds_raises() {
+        let ops = vec![Op::ListNew, int(5), Op::ListPush, int(1), Op::ListGet];
+        let result = Fiber::new(fun(ops)).run();
+        assert!(matches!(result, Err(Effect::IndexOutOfBounds { index: 1, len: 1 })));
+        let result = Fiber::new(fun(vec![Op::ListNew, int(-1), Op::ListGet])).run();
+        assert!(matches!(result, Err(Effect::IndexOutOfBounds { index: -1, len: 0 })));
+    }
+
+    #[test]
+    fn floats_compare_as_ieee() {
+        assert_eq!(Data::Float(0.0), Data::Float(-0.0));
+        assert_ne!(Data::Float(f64::NAN), Data::Float(f64::NAN));
+    }
+
+    #[test]
+    fn values_of_different_types_are_unequal() {
+        assert_ne!(Data::Int(1), Data::Float(1.0));
+        assert_ne!(Data::Unit, Data::Bool(false));
+        assert_eq!(Data::Str("a".into()), Data::Str("a".into()));
+    }
+
+    /// A list whose only item is itself.
+    fn list_holding_itself() -> Rc<RefCell<Vec<Data>>> {
+        let list = Rc::new(RefCell::new(vec![]));
+        list.borrow_mut().push(Data::List(list.clone()));
+        list
+    }
+
+    #[test]
+    fn lists_holding_themselves_compare() {
+        let ops = vec![Op::ListNew, Op::Dup, Op::Dup, Op::ListPush, Op::Drop, Op::Dup, Op::Eq];
+        assert!(run_bool(ops));
+
+        // two lists alike all the way down are equal
+        let (a, b) = (list_holding_itself(), list_holding_itself());
+        assert!(Data::List(a.clone()) == Data::List(b.clone()));
+        b.borrow_mut().push(Data::Int(1));
+        assert!(Data::List(a) != Data::List(b));
+
+        let map = Rc::new(RefCell::new(BTreeMap::new()));
+        map.borrow_mut().insert(Key::Int(0), Data::Map(map.clone()));
+        assert!(Data::Map(map.clone()) == Data::Map(map));
+    }
+
+    #[test]
+    fn functions_are_equal_only_to_themselves() {
+        let one = Data::Fun(fun(vec![Op::Add]));
+        let other = Data::Fun(fun(vec![Op::Add]));
+        assert_eq!(one, one.clone());
+        assert_ne!(one, other);
+    }
+
+    #[test]
+    fn rendering_data() {
+        let tuple = Data::Tuple(Rc::new(vec![Data::Int(1), Data::Str("a".into())]));
+        let list = Data::List(Rc::new(RefCell::new(vec![Data::Float(1.0), Data::Unit])));
+        let map = Data::Map(Rc::new(RefCell::new(
+            vec![(Key::Int(1), Data::Bool(true))].into_iter().collect()
+        )));
+        let raw = RawFun { ops: Rc::new(vec![]), num_captures: 0, spans: None };
+        let cases = [
+            (Data::Unit, "()"),
+            (Data::Float(1.0), "1.0"),
+            (Data::Float(-2.5), "-2.5"),
+            (Data::Int(3), "3"),
+            (Data::Bool(false), "false"),
+            (Data::Char('x'), "x"),
+            (Data::Str("hi".into()), "hi"),
+            (Data::Bytes(Rc::from(&b"a\n"[..])), "b\"a\\n\""),
+            (tuple, "(1, a)"),
+            (list, "[1.0, ()]"),
+            (map, "{1: true}"),
+            (Data::RawFun(raw), "<rawfun>"),
+            (Data::Fun(fun(vec![])), "<fun>"),
+            (Data::Cont(Rc::new(Fiber::new(fun(vec![])))), "<cont>"),
+            (Data::Native(Native::new(Ok)), "<native>"),
+        ];
+        for (data, rendered) in cases {
+            assert_eq!(format!("{}", data), rendered);
+        }
+    }
+
+    #[test]
+    fn rendering_a_list_inside_itself() {
+        assert_eq!(format!("{}", Data::List(list_holding_itself())), "[[...]]");
+
+        let map = Rc::new(RefCell::new(BTreeMap::new()));
+        map.borrow_mut().insert(Key::Int(0), Data::Map(map.clone()));
+        assert_eq!(format!("{}", Data::Map(map)), "{0: {...}}");
+
+        // a list in two places that isn't inside itself renders in full
+        let inner = Data::List(Rc::new(RefCell::new(vec![Data::Int(1)])));
+        let outer = Data::List(Rc::new(RefCell::new(vec![inner.clone(), inner])));
+        assert_eq!(format!("{}", outer), "[[1], [1]]");
+    }
+
+    #[test]
+    fn formatting_effects() {
+        let fatal = |span| Effect::Fatal { pc: 4, span, backtrace: vec![] };
+        let cases = [
+            (fatal(None), "fatal error: invalid bytecode at pc 4"),
+            (
+                fatal(Some(Span { line: 2, column: 7 })),
+                "fatal error: invalid bytecode at pc 4 (line 2, column 7)",
+            ),
+            (
+                Effect::TypeMismatch { expected: "Int", found: "Str" },
+                "type mismatch: expected Int, found Str",
+            ),
+            (Effect::ZeroDivision, "division by zero"),
+            (Effect::IntegerOverflow, "integer overflow"),
+            (Effect::MissingKey(Key::Str("k".into())), "missing key k"),
+            (Effect::InvalidChar(-1), "-1 is not a valid char"),
+            (Effect::InvalidUtf8 { valid_up_to: 3 }, "invalid UTF-8 after byte 3"),
+            (Effect::ContinuationAlreadyUsed, "continuation has already been resumed"),
+            (
+                Effect::DuplicateHandler(Name(1)),
+                "handler for effect 1 is already installed",
+            ),
+            (Effect::StackOverflow, "stack overflow"),
+            (Effect::OutOfFuel, "out of fuel"),
+            (Effect::Yielded, "yielded"),
+            (
+                Effect::IndexOutOfBounds { index: 3, len: 2 },
+                "index 3 out of bounds for length 2",
+            ),
+            (
+                Effect::Virtual { name: Name(0), symbol: None, args: vec![Data::Int(1), Data::Unit] },
+                "unhandled effect 0 with arguments (1, ())",
+            ),
+            (
+                Effect::Virtual { name: Name(0), symbol: Some("log".into()), args: vec![] },
+                "unhandled effect log (0) with arguments ()",
+            ),
+        ];
+        for (effect, formatted) in cases {
+            assert_eq!(format!("{}", effect), formatted);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn effect_is_an_error() {
+        let error: Box<dyn std::error::Error> = Box::new(Effect::Io(io::ErrorKind::WriteZero));
+        assert_eq!(error.to_string(), "could not write output: WriteZero");
+    }
+
+    #[test]
+    fn underflow_reports_its_pc_and_span() {
+        let mut fiber = Fiber::new(fun(vec![int(1), Op::Add]));
+        assert!(matches!(fiber.run(), Err(Effect::Fatal { pc: 1, span: None, .. })));
+
+        let spans = vec![Span { line: 1, column: 1 }, Span { line: 2, column: 5 }];
+        let fun = Fun {
+            ops: Rc::new(vec![int(1), Op::Add]),
+            captures: Rc::new(vec![]),
+            spans: Some(Rc::new(spans)),
+        };
+        match Fiber::new(fun).run() {
+            Err(Effect::Fatal { pc, span, .. }) => {
+                assert_eq!(pc, 1);
+                assert_eq!(span, Some(Span { line: 2, column: 5 }));
+            },
+            other => panic!("expected a fatal error, got {:?}", other),
+        }
+    }
+
+    /// A fiber that has installed `handler` with `install`, and no more.
+    fn handling(install: Op, handler: Fun) -> Fiber {
+        let mut fiber = Fiber::new(fun(vec![
+            Op::Const(Data::Fun(handler)),
+            install,
+            Op::Const(Data::Unit),
+        ]));
+        fiber.step().unwrap();
+        fiber.step().unwrap();
+        fiber
+    }
+
+    #[test]
+    fn effect_reaches_a_handler_two_fibers_up() {
+        let handler = fun(vec![int(10), Op::Mul, Op::Resume, Op::Return(1)]);
+        let grandparent = handling(Op::Handler(Name(2)), handler);
+        let parent = Fiber::new(fun(vec![])).with_parent(&Rc::new(grandparent));
+
+        let child = fun(vec![int(5), Op::Raise(Name(2)), int(1), Op::Add]);
+        let mut child = Fiber::new(child).with_parent(&Rc::new(parent));
+        assert_eq!(child.run().unwrap(), Data::Int(51));
+    }
+
+    #[test]
+    fn effect_no_fiber_handles_reaches_the_host() {
+        let handler = fun(vec![Op::Resume, Op::Return(1)]);
+        let parent = Rc::new(handling(Op::Handler(Name(0)), handler));
+        let mut fiber = Fiber::new(fun(vec![int(5), Op::Raise(Name(1))])).with_parent(&parent);
+        match fiber.run() {
+            Err(Effect::Virtual { name, args, .. }) => {
+                assert_eq!(name, Name(1));
+                assert_eq!(args, [Data::Int(5)]);
+            },
+            other => panic!("expected the raise to escape, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn handler_resumes_a_continuation_twice() {
+        // the rest of the body adds 10; the handler runs it with 1 and 2
+        let fun = assemble("
+            fun drop clone_cont const 1 resume swap const 2 resume add return 1 end
+            handler 0 const unit raise 0 const 10 add
+        ").unwrap();
+        assert_eq!(Fiber::new(fun).run().unwrap(), Data::Int(23));
+    }
+
+    #[test]
+    fn resuming_the_same_continuation_twice_fails() {
+        let fun = assemble("
+            fun drop dup const 1 resume drop const 2 resume return 1 end
+            handler 0 const unit raise 0
+        ").unwrap();
+        assert!(matches!(Fiber::new(fun).run(), Err(Effect::ContinuationAlreadyUsed)));
+    }
+
+    #[test]
+    fn cloning_a_used_continuation_fails() {
+        let fun = assemble("
+            fun drop dup const 1 resume drop clone_cont return 1 end
+            handler 0 const unit raise 0
+        ").unwrap();
+        assert!(matches!(Fiber::new(fun).run(), Err(Effect::ContinuationAlreadyUsed)));
+    }
+
+    #[test]
+    fn return_clause_doubles_the_result() {
+        let double = fun(vec![int(2), Op::Mul, Op::Return(1)]);
+        let body = fun(vec![
+            Op::Const(Data::Fun(double)),
+            Op::HandleReturn,
+            int(21),
+            Op::Return(1),
+        ]);
+        assert_eq!(run_int(vec![Op::Const(Data::Fun(body)), Op::Const(Data::Unit), Op::Call]), 42);
+    }
+
+    #[test]
+    fn return_clause_runs_after_the_handled_effects() {
+        let double = fun(vec![int(2), Op::Mul, Op::Return(1)]);
+        let handler = fun(vec![Op::Pop(1), int(5), Op::Resume, Op::Return(1)]);
+        let body = fun(vec![
+            Op::Const(Data::Fun(double)),
+            Op::HandleReturn,
+            Op::Const(Data::Fun(handler)),
+            Op::Handler(Name(0)),
+            Op::Const(Data::Unit),
+            Op::Raise(Name(0)),
+            Op::Return(1),
+        ]);
+        assert_eq!(run_int(vec![
+            Op::Const(Data::Fun(body)),
+            Op::Const(Data::Unit),
+            Op::Call,
+            int(1),
+            Op::Add,
+        ]), 11);
+    }
+
+    fn raise_twice_in_a_loop(install: Op) -> Result<Data, Effect> {
+        // sums what the handler resumes with, over two iterations
+        let handler = fun(vec![Op::Pop(1), int(1), Op::Resume, Op::Return(1)]);
+        Fiber::new(fun(vec![
+            Op::Const(Data::Fun(handler)),
+            install,
+            int(0), Op::Set(Name(0)), int(0), Op::Set(Name(1)),
+            // top:
+            Op::Get(Name(0)), int(2), Op::Lt, Op::JumpIfFalse(20),
+            Op::Const(Data::Unit), Op::Raise(Name(0)),
+            Op::Get(Name(1)), Op::Add, Op::Set(Name(1)),
+            Op::Get(Name(0)), int(1), Op::Add, Op::Set(Name(0)),
+            Op::Jump(6),
+            // done:
+            Op::Get(Name(1)),
+        ])).run()
+    }
+
+    #[test]
+    fn deep_handler_catches_every_raise() {
+        assert_eq!(raise_twice_in_a_loop(Op::Handler(Name(0))).unwrap(), Data::Int(2));
+    }
+
+    #[test]
+    fn shallow_handler_catches_only_the_first_raise() {
+        match raise_twice_in_a_loop(Op::ShallowHandler(Name(0))) {
+            Err(Effect::Virtual { name, .. }) => assert_eq!(name, Name(0)),
+            other => panic!("expected the second raise to escape, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn shallow_handler_in_a_parent_fires_once() {
+        // the parent handles 1 once; the second raise escapes
+        let handler = fun(vec![int(1), Op::Add, Op::Resume, Op::Return(1)]);
+        let parent = Rc::new(handling(Op::ShallowHandler(Name(1)), handler));
+        let child = fun(vec![int(0), Op::Raise(Name(1)), Op::Raise(Name(1))]);
+        match Fiber::new(child).with_parent(&parent).run() {
+            Err(Effect::Virtual { name, args, .. }) => {
+                assert_eq!(name, Name(1));
+                assert_eq!(args, [Data::Int(1)]);
+            },
+            other => panic!("expected the second raise to escape, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn resuming_carries_on_after_the_raise_exactly_once() {
+        // the handler is shallow, so were the raise run again, it would escape
+        let handler = fun(vec![Op::Drop, int(1), Op::Resume, Op::Return(1)]);
+        assert_eq!(run_int(vec![
+            Op::Const(Data::Fun(handler)),
+            Op::ShallowHandler(Name(0)),
+            Op::Const(Data::Unit),
+            Op::Raise(Name(0)),
+            int(10),
+            Op::Add,
+        ]), 11);
+    }
+
+    #[test]
+    fn self_recursion_overflows_the_stack() {
+        // the function calls whatever it is given, and is given itself
+        let recurse = fun(vec![Op::Dup, Op::Call, Op::Return(1)]);
+        let main = fun(vec![Op::Const(Data::Fun(recurse)), Op::Dup, Op::Call]);
+        assert!(matches!(Fiber::new(main.clone()).run(), Err(Effect::StackOverflow)));
+        let mut fiber = Fiber::new(main).with_max_depth(3);
+        assert!(matches!(fiber.run(), Err(Effect::StackOverflow)));
+        assert_eq!(fiber.stack.frames.len(), 3);
+    }
+
+    #[test]
+    fn fuel_stops_an_infinite_loop() {
+        let mut fiber = Fiber::new(fun(vec![Op::Jump(0)])).with_fuel(100);
+        assert!(matches!(fiber.run(), Err(Effect::OutOfFuel)));
+        fiber.add_fuel(100);
+        assert!(matches!(fiber.run(), Err(Effect::OutOfFuel)));
+    }
+
+    #[test]
+    fn topped_up_fiber_carries_on() {
+        let mut fiber = Fiber::new(fun(vec![int(1), int(2), Op::Add])).with_fuel(2);
+        assert!(matches!(fiber.run(), Err(Effect::OutOfFuel)));
+        assert_eq!(fiber.stack.datum, [Data::Int(1), Data::Int(2)]);
+        fiber.add_fuel(1);
+        assert_eq!(fiber.run().unwrap(), Data::Int(3));
+    }
+
+    #[test]
+    fn disassembling_the_demo() {
+        let text = disassemble(&demo().ops);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 9);
+        assert_eq!(lines[0], "0000  Const     Float(3.0)");
+        assert_eq!(lines[3], "0003  Add");
+        assert_eq!(lines[5], "0005  Const     <fun> captures 0 ops 1");
+        assert_eq!(lines[6], "    0000  Call");
+        assert_eq!(lines[7], "0006  Handler   Name(0)");
+        assert_eq!(lines[8], "0007  Raise     Name(0)");
+    }
+
+    #[test]
+    fn builder_resolves_forward_and_backward_jumps() {
+        // sums 3, 2, and 1
+        let mut builder = FunBuilder::new();
+        let (top, done) = (builder.label(), builder.label());
+        builder
+            .push(int(3)).push(Op::Set(Name(0)))
+            .push(int(0)).push(Op::Set(Name(1)))
+            .mark(top)
+            .push(Op::Get(Name(0))).push(int(0)).push(Op::Gt)
+            .jump_if_false_to(done)
+            .push(Op::Get(Name(1))).push(Op::Get(Name(0))).push(Op::Add).push(Op::Set(Name(1)))
+            .push(Op::Get(Name(0))).push(int(1)).push(Op::Sub).push(Op::Set(Name(0)))
+            .jump_to(top)
+            .mark(done)
+            .push(Op::Get(Name(1)));
+        let fun = builder.build();
+        assert!(matches!(fun.ops[7], Op::JumpIfFalse(17)));
+        assert!(matches!(fun.ops[16], Op::Jump(4)));
+        assert_eq!(Fiber::new(fun).run().unwrap(), Data::Int(6));
+    }
+
+    #[test]
+    #[should_panic(expected = "never marked")]
+    fn builder_panics_on_an_unmarked_label() {
+        let mut builder = FunBuilder::new();
+        let nowhere = builder.label();
+        builder.jump_to(nowhere).build();
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn program_round_trips_through_bytes() {
+        let inner = fun(vec![float(1.5), Op::Add, Op::Return(1)]);
+        let program = Program::new(fun(vec![
+            Op::Const(Data::Fun(inner)),
+            float(2.0),
+            Op::Call,
+        ]));
+        let loaded = Program::from_bytes(&program.to_bytes().unwrap()).unwrap();
+        assert_eq!(disassemble(&loaded.entry.ops), disassemble(&program.entry.ops));
+        assert_eq!(loaded.run().unwrap(), Data::Float(3.5));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serializing_a_continuation_fails() {
+        let cont = Data::Cont(Rc::new(Fiber::new(fun(vec![]))));
+        let program = Program::new(fun(vec![Op::Const(cont)]));
+        assert!(program.to_bytes().is_err());
+    }
+
+    #[test]
+    fn calling_a_native_function() {
+        let increment = Native::new(|data| match data {
+            Data::Float(n) => Ok(Data::Float(n + 1.0)),
+            other => Err(Effect::mismatch("Float", &other)),
+        });
+        let mut fiber = Fiber::new(fun(vec![
+            Op::Const(Data::Native(increment.clone())),
+            float(2.5),
+            Op::Call,
+        ]));
+        assert_eq!(fiber.run().unwrap(), Data::Float(3.5));
+
+        let mut fiber = Fiber::new(fun(vec![
+            Op::Const(Data::Native(increment)),
+            int(2),
+            Op::Call,
+        ]));
+        assert!(matches!(fiber.run(), Err(Effect::TypeMismatch { expected: "Float", found: "Int" })));
+    }
+
+    #[test]
+    fn host_callback_satisfies_a_raise() {
+        let fun = fun(vec![Op::Const(Data::Unit), Op::Raise(Name(3)), int(1), Op::Add]);
+        let mut fiber = Fiber::new(fun).with_host(Name(3), |_| Ok(Data::Int(41)));
+        assert_eq!(fiber.run().unwrap(), Data::Int(42));
+    }
+
+    #[test]
+    fn fiber_handler_comes_before_the_host() {
+        let handler = fun(vec![Op::Drop, int(1), Op::Resume, Op::Return(1)]);
+        let fun = fun(vec![
+            Op::Const(Data::Fun(handler)),
+            Op::Handler(Name(3)),
+            Op::Const(Data::Unit),
+            Op::Raise(Name(3)),
+        ]);
+        let mut fiber = Fiber::new(fun).with_host(Name(3), |_| Ok(Data::Int(41)));
+        assert_eq!(fiber.run().unwrap(), Data::Int(1));
+    }
+
+    #[test]
+    fn tail_recursive_countdown_runs_in_constant_depth() {
+        // the function takes a tuple of itself and the count
+        let countdown = fun(vec![
+            Op::Unpack,
+            Op::Set(Name(1)),
+            Op::Set(Name(0)),
+            Op::Get(Name(1)), int(0), Op::Eq, Op::JumpIfFalse(9),
+            Op::Get(Name(1)), Op::Return(1),
+            Op::Get(Name(0)), Op::Get(Name(0)), Op::Get(Name(1)), int(1), Op::Sub,
+            Op::Pack(2),
+            Op::TailCall,
+        ]);
+        let fun = fun(vec![
+            Op::Const(Data::Fun(countdown)),
+            Op::Dup,
+            int(10000),
+            Op::Pack(2),
+            Op::Call,
+        ]);
+        let mut fiber = Fiber::new(fun).with_max_depth(4);
+        assert_eq!(fiber.run().unwrap(), Data::Int(0));
+    }
+
+    #[test]
+    fn pooled_constants_share_storage() {
+        let mut program = Program::new(fun(vec![Op::ConstIdx(0), Op::ConstIdx(0), Op::Pack(2)]));
+        program.constants.push(Data::Str("a long string".into()));
+        let pair = match program.fiber().run().unwrap() {
+            Data::Tuple(pair) => pair,
+            other => panic!("expected a Tuple, got {:?}", other),
+        };
+        match &pair[..] {
+            [Data::Str(a), Data::Str(b)] => assert!(Rc::ptr_eq(a, b)),
+            other => panic!("expected two strings, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn constant_past_the_end_of_the_pool_is_fatal() {
+        let program = Program::new(fun(vec![Op::ConstIdx(1)]));
+        assert!(matches!(program.fiber().run(), Err(Effect::Fatal { pc: 0, .. })));
+    }
+
+    #[test]
+    fn verify_accepts_a_valid_function() {
+        let looping = fun(vec![int(1), Op::Set(Name(0)), Op::Get(Name(0)), Op::JumpIfFalse(2)]);
+        assert_eq!(verify(&looping), Ok(()));
+        assert_eq!(verify(&demo()), Ok(()));
+    }
+
+    #[test]
+    fn verify_rejects_each_kind_of_error() {
+        let jump = fun(vec![Op::Jump(2)]);
+        assert_eq!(verify(&jump), Err(VerifyError::JumpOutOfRange { pc: 0, target: 2 }));
+        let get = fun(vec![Op::Get(Name(0))]);
+        assert_eq!(verify(&get), Err(VerifyError::UnboundSlot { pc: 0, slot: 0 }));
+        let set = fun(vec![int(1), Op::Set(Name(usize::MAX))]);
+        assert_eq!(
+            verify(&set),
+            Err(VerifyError::SlotOutOfRange { pc: 1, slot: usize::MAX }),
+        );
+        let underflow = fun(vec![int(1), Op::Add]);
+        assert_eq!(verify(&underflow), Err(VerifyError::StackUnderflow { pc: 1 }));
+        let mismatch = fun(vec![Op::Const(Data::Bool(true)), Op::JumpIfFalse(3), int(1)]);
+        assert_eq!(verify(&mismatch), Err(VerifyError::StackMismatch { pc: 3 }));
+    }
+
+    #[test]
+    fn accessors_show_a_stepped_fiber() {
+        let mut fiber = Fiber::new(demo());
+        assert_eq!(fiber.pc(), 0);
+        assert_eq!(fiber.frame_count(), 1);
+        assert!(matches!(fiber.current_op(), Some(Op::Const(Data::Float(_)))));
+        fiber.step().unwrap();
+        assert_eq!(fiber.pc(), 1);
+        assert_eq!(fiber.stack_data(), [Data::Float(3.0)]);
+        assert_eq!(fiber.frame_count(), 1);
+        assert!(matches!(fiber.current_op(), Some(Op::Const(Data::Float(_)))));
+    }
+
+    #[test]
+    fn truth_tables() {
+        let run = |ops| Fiber::new(fun(ops)).run().unwrap();
+        let bool = |b| Op::Const(Data::Bool(b));
+        for a in [false, true] {
+            assert_eq!(run(vec![bool(a), Op::Not]), Data::Bool(!a));
+            for b in [false, true] {
+                assert_eq!(run(vec![bool(a), bool(b), Op::And]), Data::Bool(a && b));
+                assert_eq!(run(vec![bool(a), bool(b), Op::Or]), Data::Bool(a || b));
+            }
+        }
+    }
+
+    #[test]
+    fn logic_on_a_non_bool_is_a_type_mismatch() {
+        let run = |ops| Fiber::new(fun(ops)).run();
+        let yes = || Op::Const(Data::Bool(true));
+        assert!(matches!(run(vec![yes(), int(1), Op::And]), Err(Effect::TypeMismatch { .. })));
+        assert!(matches!(run(vec![int(1), yes(), Op::Or]), Err(Effect::TypeMismatch { .. })));
+        assert!(matches!(run(vec![int(1), Op::Not]), Err(Effect::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn printing_two_values_into_a_buffer() {
+        let (buffer, sink) = buffer();
+        let fun = fun(vec![
+            float(1.5), Op::Print, string("hi"), Op::Print, Op::Const(Data::Unit),
+        ]);
+        let mut fiber = Fiber::new(fun).with_output(sink);
+        assert_eq!(fiber.run().unwrap(), Data::Unit);
+        assert_eq!(contents(&buffer), "1.5\nhi\n");
+    }
+
+    #[test]
+    fn trace_writes_nothing_when_off() {
+        let (written, output) = buffer();
+        let mut fiber = Fiber::new(fun(vec![int(1), int(2), Op::Add, Op::Print, int(3)]))
+            .with_output(output);
+        assert_eq!(fiber.run().unwrap(), Data::Int(3));
+        assert_eq!(contents(&written), "3\n");
+    }
+
+    #[test]
+    fn trace_writes_each_op_to_the_output() {
+        let (written, output) = buffer();
+        let mut fiber = Fiber::new(fun(vec![int(1), int(2), Op::Add]))
+            .with_output(output)
+            .with_trace(true);
+        fiber.run().unwrap();
+
+        let written = contents(&written);
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines, [
+            "0000  Const(Int(1))  (depth 0)",
+            "0001  Const(Int(2))  (depth 1)",
+            "0002  Add  (depth 2)",
+        ]);
+    }
+
+    #[test]
+    fn reset_runs_the_same_program_again() {
+        let (buffer, sink) = buffer();
+        let fun = fun(vec![
+            int(2), Op::Set(Name(0)),
+            Op::Get(Name(0)), Op::Print,
+            Op::Get(Name(0)), int(3), Op::Mul,
+        ]);
+        let mut fiber = Fiber::new(fun).with_output(sink);
+        assert_eq!(fiber.run().unwrap(), Data::Int(6));
+        fiber.reset();
+        assert_eq!(fiber.pc(), 0);
+        assert!(fiber.stack_data().is_empty());
+        assert_eq!(fiber.frame_count(), 1);
+        assert_eq!(fiber.run().unwrap(), Data::Int(6));
+        assert_eq!(contents(&buffer), "2\n2\n");
+    }
 
-        let frame = Frame::new(
-            Some(suspend),
-            self.stack.datum.len(),
-            fun.captures,
+    #[test]
+    fn stepping_borrows_the_op_it_runs() {
+        // a native called mid-step sees how many handles there are
+        // to the function's ops, and to a value in one of them
+        let payload: Rc<str> = "payload".into();
+        let probe_ops: Rc<RefCell<alloc::rc::Weak<Vec<Op>>>> = Rc::default();
+        let counts = Rc::new(RefCell::new(vec![]));
+        let probe = {
+            let (probe_ops, counts) = (probe_ops.clone(), counts.clone());
+            let payload = payload.clone();
+            Native::new(move |_| {
+                let ops = probe_ops.borrow().strong_count();
+                counts.borrow_mut().push((ops, Rc::strong_count(&payload)));
+                Ok(Data::Unit)
+            })
+        };
+        let ops = Rc::new(vec![
+            Op::Const(Data::Native(probe)),
+            int(0),
+            Op::Call,
+            Op::Jump(5),
+            Op::Const(Data::Str(payload.clone())),
+        ]);
+        *probe_ops.borrow_mut() = Rc::downgrade(&ops);
+
+        let entry = Fun { ops: ops.clone(), captures: Rc::new(vec![]), spans: None };
+        let mut fiber = Fiber::new(entry);
+        // the ops are held by the fiber, its entry, and this test,
+        // and the payload by this test, its op, and the probe
+        let at_rest = (Rc::strong_count(&ops), Rc::strong_count(&payload));
+        assert_eq!(at_rest, (3, 3));
+        assert_eq!(fiber.run().unwrap(), Data::Unit);
+        assert_eq!(*counts.borrow(), [at_rest]);
+    }
+
+    #[test]
+    fn indexing_a_string_is_by_char() {
+        let index = |i| Fiber::new(fun(vec![string("héllo"), int(i), Op::StrIndex])).run();
+        assert_eq!(index(1).unwrap(), Data::Char('é'));
+        assert_eq!(index(2).unwrap(), Data::Char('l'));
+        assert!(matches!(index(5), Err(Effect::IndexOutOfBounds { index: 5, len: 5 })));
+    }
+
+    #[test]
+    fn converting_between_char_and_int() {
+        let run = |data, op| Fiber::new(fun(vec![Op::Const(data), op])).run();
+        assert_eq!(run(Data::Char('A'), Op::CharToInt).unwrap(), Data::Int(65));
+        assert_eq!(run(Data::Int(233), Op::IntToChar).unwrap(), Data::Char('é'));
+        let surrogate = run(Data::Int(55296), Op::IntToChar);
+        assert!(matches!(surrogate, Err(Effect::InvalidChar(55296))));
+        assert!(matches!(run(Data::Int(65), Op::CharToInt), Err(Effect::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn map_set_then_get() {
+        let map = || vec![
+            Op::MapNew,
+            string("a"), int(1), Op::MapSet,
+            int(2), string("two"), Op::MapSet,
+        ];
+        let get = |key| Fiber::new(fun([map(), vec![key, Op::MapGet]].concat())).run();
+        assert_eq!(get(string("a")).unwrap(), Data::Int(1));
+        assert_eq!(get(int(2)).unwrap(), Data::Str("two".into()));
+        // `Int` keys order before `Str` keys
+        let whole = Fiber::new(fun(map())).run().unwrap();
+        assert_eq!(format!("{}", whole), "{2: two, a: 1}");
+    }
+
+    #[test]
+    fn map_get_of_a_missing_key_raises() {
+        let ops = vec![Op::MapNew, string("a"), int(1), Op::MapSet, string("b"), Op::MapGet];
+        match Fiber::new(fun(ops)).run() {
+            Err(Effect::MissingKey(key)) => assert_eq!(format!("{}", key), "b"),
+            other => panic!("expected a missing key, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn map_key_must_be_an_int_or_a_str() {
+        let ops = vec![Op::MapNew, Op::Const(Data::Bool(true)), int(1), Op::MapSet];
+        assert!(matches!(Fiber::new(fun(ops)).run(), Err(Effect::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn dropped_continuation_leaves_its_handlers_in_scope() {
+        // the handler for 0 drops its continuation, then raises 1,
+        // which the outer frame's handler catches, and aborts too
+        let fun = assemble("
+            fun drop drop const 100 return 1 end handler 1
+            fun
+                fun swap drop raise 1 return 1 end handler 0
+                const 5 raise 0 return 1
+            end
+            const unit call return 1
+        ").unwrap();
+        assert_eq!(Fiber::new(fun).run().unwrap(), Data::Int(100));
+
+        // with no handler for 1, it goes to the host
+        let fun = assemble("fun swap drop raise 1 return 1 end handler 0 const 5 raise 0").unwrap();
+        let mut fiber = Fiber::new(fun).with_host(Name(1), |data| match data {
+            Data::Int(n) => Ok(Data::Int(n * 2)),
+            other => Err(Effect::mismatch("Int", &other)),
+        });
+        assert_eq!(fiber.run().unwrap(), Data::Int(10));
+    }
+
+    #[test]
+    fn resumed_continuation_is_freed() {
+        let seen: Rc<RefCell<Option<alloc::rc::Weak<Fiber>>>> = Rc::default();
+        let record = {
+            let seen = seen.clone();
+            Native::new(move |data| match data {
+                Data::Cont(cont) => {
+                    *seen.borrow_mut() = Some(Rc::downgrade(&cont));
+                    Ok(Data::Unit)
+                },
+                other => Err(Effect::mismatch("Cont", &other)),
+            })
+        };
+
+        // the handler shows the host its continuation, then resumes it
+        let handler = fun(vec![
+            Op::Drop,
+            Op::Dup,
+            Op::Const(Data::Native(record)),
+            Op::Swap,
+            Op::Call,
+            Op::Drop,
+            int(7),
+            Op::Resume,
+            Op::Return(1),
+        ]);
+        let mut fiber = Fiber::new(fun(vec![
+            Op::Const(Data::Fun(handler)),
+            Op::Handler(Name(0)),
+            int(1),
+            Op::Raise(Name(0)),
+            Op::Return(1),
+        ]));
+        assert_eq!(fiber.run().unwrap(), Data::Int(7));
+
+        let cont = seen.borrow_mut().take().unwrap();
+        assert_eq!(cont.strong_count(), 0);
+    }
+
+    #[test]
+    fn snapshot_is_unaffected_by_the_original() {
+        let ops = vec![Op::ListNew, int(1), Op::ListPush, int(2), Op::ListPush, Op::ListLen];
+        // running out of fuel pauses the fiber after the first push
+        let mut fiber = Fiber::new(fun(ops)).with_fuel(3);
+        assert!(matches!(fiber.run(), Err(Effect::OutOfFuel)));
+        let mut snapshot = fiber.snapshot();
+
+        fiber.add_fuel(10);
+        assert_eq!(fiber.run().unwrap(), Data::Int(2));
+        match &snapshot.stack.datum[..] {
+            [Data::List(list)] => assert_eq!(*list.borrow(), [Data::Int(1)]),
+            other => panic!("expected a list, got {:?}", other),
+        }
+        snapshot.add_fuel(10);
+        assert_eq!(snapshot.run().unwrap(), Data::Int(2));
+    }
+
+    #[test]
+    fn snapshot_and_original_can_both_resume_a_continuation() {
+        let handler = fun(vec![Op::Resume, Op::Return(1)]);
+        let main = fun(vec![
+            Op::Const(Data::Fun(handler)),
+            Op::Handler(Name(0)),
+            int(1),
+            Op::Raise(Name(0)),
+            int(1),
+            Op::Add,
+        ]);
+        // pauses in the handler, before it resumes
+        let mut fiber = Fiber::new(main).with_fuel(4);
+        assert!(matches!(fiber.run(), Err(Effect::OutOfFuel)));
+        let mut snapshot = fiber.snapshot();
+
+        fiber.add_fuel(10);
+        assert_eq!(fiber.run().unwrap(), Data::Int(2));
+        snapshot.add_fuel(10);
+        assert_eq!(snapshot.run().unwrap(), Data::Int(2));
+    }
+
+    #[test]
+    fn snapshot_copies_the_handler_waiting_on_a_continuation() {
+        // the handler keeps a list while the continuation it resumed runs
+        let src = "
+            fun list_new set 0 resume drop get 0 const 1 list_push list_len return 1 end
+            handler 0
+            const 1 raise 0 const 1 add
+        ";
+        // pauses in the continuation, with the handler waiting on it
+        let mut fiber = Fiber::new(assemble(src).unwrap()).with_fuel(8);
+        assert!(matches!(fiber.run(), Err(Effect::OutOfFuel)));
+        let mut snapshot = fiber.snapshot();
+
+        fiber.add_fuel(10);
+        assert_eq!(fiber.run().unwrap(), Data::Int(1));
+        snapshot.add_fuel(10);
+        assert_eq!(snapshot.run().unwrap(), Data::Int(1));
+    }
+
+    #[test]
+    fn calling_a_continuation_does_not_come_back() {
+        // resuming runs the rest of the body, whose result comes back
+        // to the handler, which then carries on
+        let handler = |op| format!("
+            fun drop const 5 {} const 1 add return 1 end
+            handler 0 const unit raise 0 const 10 mul
+        ", op);
+        assert_eq!(run(&handler("resume")).unwrap(), Data::Int(51));
+
+        // calling runs the rest of the body in place of the handler,
+        // whose result is the result of the whole
+        assert_eq!(run(&handler("call")).unwrap(), Data::Int(50));
+    }
+
+    #[test]
+    fn called_continuation_finishes_for_the_fiber_that_resumed_its_caller() {
+        // the inner handler calls its continuation, so the outer handler,
+        // which resumed the inner one's raise, gets its result
+        let result = run("
+            fun drop const 2 resume const 100 add return 1 end handler 1
+            fun
+                fun drop const unit raise 1 call const 1000 add return 1 end handler 0
+                const 3 raise 0 const 10 mul return 1
+            end
+            const unit call
+        ");
+        assert_eq!(result.unwrap(), Data::Int(120));
+    }
+
+    #[test]
+    fn resume_is_only_for_continuations() {
+        let identity = || Op::Const(Data::Fun(fun(vec![Op::Return(1)])));
+        let result = Fiber::new(fun(vec![identity(), int(1), Op::Resume])).run();
+        assert!(matches!(result, Err(Effect::TypeMismatch { .. })));
+        let result = Fiber::new(fun(vec![identity(), int(1), Op::Call])).run();
+        assert_eq!(result.unwrap(), Data::Int(1));
+    }
+
+    #[test]
+    fn events_of_a_short_program() {
+        let mut fiber = Fiber::new(assemble("const 1 const 2 add").unwrap());
+        let events: Vec<Event> = fiber.events().collect::<Result<_, _>>().unwrap();
+        let pcs: Vec<usize> = events.iter().map(|event| event.pc).collect();
+        let depths: Vec<usize> = events.iter().map(|event| event.depth).collect();
+        assert_eq!(pcs, [0, 1, 2]);
+        // the result is taken off the stack once the fiber finishes
+        assert_eq!(depths, [1, 2, 0]);
+        assert!(matches!(events[2].op, Op::Add));
+        assert_eq!(events[1].result, None);
+        assert_eq!(events[2].result, Some(Data::Int(3)));
+    }
+
+    #[test]
+    fn events_stop_after_an_error() {
+        let mut fiber = Fiber::new(assemble("const 1 add const 2").unwrap());
+        let mut events = fiber.events();
+        assert!(matches!(events.next(), Some(Ok(Event { pc: 0, .. }))));
+        assert!(matches!(events.next(), Some(Err(Effect::Fatal { pc: 1, .. }))));
+        assert!(events.next().is_none());
+    }
+
+    #[test]
+    fn mixed_operands_name_both_types() {
+        let message = |src| format!("{}", run(src).unwrap_err());
+        assert_eq!(message("const 1.0 const 2 mul"), "type mismatch: expected Float, found Int");
+        assert_eq!(message("const 1 const 2.0 div"), "type mismatch: expected Int, found Float");
+        assert_eq!(message("const 1 const 2.0 lt"), "type mismatch: expected Int, found Float");
+        assert_eq!(
+            message("const true const 2 add"),
+            "type mismatch: expected Float or Int, found Bool",
         );
-        self.stack.frames.push(frame);
+    }
+
+    #[test]
+    fn closure_op_captures_and_calls() {
+        let raw = RawFun {
+            ops: Rc::new(vec![Op::Get(Name(0)), Op::Add, Op::Return(1)]),
+            num_captures: 1,
+            spans: None,
+        };
+        let mut fiber = Fiber::new(fun(vec![
+            int(1),
+            int(40),
+            Op::Closure(raw),
+            int(2),
+            Op::Call,
+        ]));
+        assert_eq!(fiber.run().unwrap(), Data::Int(42));
+        // only the one capture was taken off the stack
+        assert_eq!(fiber.stack_data(), [Data::Int(1)]);
+    }
+
+    #[test]
+    fn capture_still_makes_the_same_closure() {
+        let closure = run("const 40 closure 1 get 0 add return 1 end const 2 call");
+        let capture = run("const 40 rawfun 1 get 0 add return 1 end capture const 2 call");
+        assert_eq!(closure.unwrap(), Data::Int(42));
+        assert_eq!(capture.unwrap(), Data::Int(42));
+    }
+
+    #[test]
+    fn host_resumes_a_suspended_effect() {
+        let fiber = Fiber::new(assemble("const 1 const 2 raise_n ask 2 const 1 add").unwrap());
+        let suspension = match fiber.run_suspending().unwrap() {
+            Outcome::Suspended(suspension) => suspension,
+            other => panic!("expected a suspension, got {:?}", other),
+        };
+        assert_eq!(suspension.name, Name(0));
+        assert_eq!(suspension.args, [Data::Int(1), Data::Int(2)]);
+        match suspension.resume(Data::Int(41)).unwrap() {
+            Outcome::Done(data) => assert_eq!(data, Data::Int(42)),
+            other => panic!("expected it to finish, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn second_handler_for_a_name_in_one_frame_is_an_error() {
+        let result = run("fun return 1 end handler 0 fun return 1 end handler 0");
+        assert!(matches!(result, Err(Effect::DuplicateHandler(Name(0)))));
+    }
+
+    #[test]
+    fn handler_in_an_inner_frame_shadows_the_outer_one() {
+        let result = run("
+            fun drop const 1 resume return 1 end handler 0
+            fun
+                fun drop const 2 resume return 1 end handler 0
+                const unit raise 0 return 1
+            end
+            const unit call
+        ");
+        assert_eq!(result.unwrap(), Data::Int(2));
+    }
+
+    #[test]
+    fn pop_n_past_the_bottom_leaves_the_stack_untouched() {
+        let mut fiber = Fiber::new(assemble("const 1 const 2 const 3").unwrap()).with_fuel(2);
+        assert!(matches!(fiber.run(), Err(Effect::OutOfFuel)));
+        assert!(matches!(fiber.pop_n(3), Err(Effect::Fatal { .. })));
+        assert_eq!(fiber.stack_data(), [Data::Int(1), Data::Int(2)]);
+        assert_eq!(fiber.pop_n(2).unwrap(), [Data::Int(1), Data::Int(2)]);
+        assert_eq!(fiber.stack_data(), []);
+    }
+
+    #[test]
+    fn pop_past_the_bottom_leaves_the_stack_untouched() {
+        let mut fiber = Fiber::new(assemble("const 1 const 2 pop 5").unwrap());
+        assert!(matches!(fiber.run(), Err(Effect::Fatal { pc: 2, .. })));
+        assert_eq!(fiber.stack_data(), [Data::Int(1), Data::Int(2)]);
+        assert_eq!(run("const 1 const 2 const 3 pop 2").unwrap(), Data::Int(1));
+    }
+
+    #[test]
+    fn handler_at_the_root_of_a_long_chain_resolves() {
+        let handler = assemble("drop const 7 resume return 1").unwrap();
+        let root = handling(Op::Handler(Name(0)), handler);
+
+        // deeper than resolving recursively could go on a test thread
+        let mut chain = vec![Rc::new(root)];
+        for _ in 0..100_000 {
+            let child = Fiber::new(fun(vec![])).with_parent(chain.last().unwrap());
+            chain.push(Rc::new(child));
+        }
+        let mut leaf = Fiber::new(assemble("const 1 raise 0 const 1 add").unwrap())
+            .with_parent(chain.last().unwrap())
+            .with_max_depth(200_000);
+        assert_eq!(leaf.depth(), 100_002);
+        assert_eq!(leaf.run().unwrap(), Data::Int(8));
+    }
+
+    #[test]
+    fn long_chain_counts_towards_max_depth() {
+        let mut chain = vec![Rc::new(Fiber::new(fun(vec![])))];
+        for _ in 0..10 {
+            let child = Fiber::new(fun(vec![])).with_parent(chain.last().unwrap());
+            chain.push(Rc::new(child));
+        }
+        let mut leaf = Fiber::new(assemble("fun return 1 end const 1 call").unwrap())
+            .with_parent(chain.last().unwrap())
+            .with_max_depth(12);
+        assert!(matches!(leaf.run(), Err(Effect::StackOverflow)));
+    }
+
+    #[test]
+    fn calling_a_two_argument_function() {
+        assert_eq!(run("fun add return 1 end const 2 const 3 call_n 2").unwrap(), Data::Int(5));
+        // the arguments are in order, and can be set to slots
+        let sub = "fun set 1 set 0 get 0 get 1 sub return 1 end";
+        let result = run(&format!("{} const 10 const 3 call_n 2", sub));
+        assert_eq!(result.unwrap(), Data::Int(7));
+    }
+
+    #[test]
+    fn returning_cleans_up_the_arguments() {
+        let src = "const 9 fun drop return 1 end const 2 const 3 call_n 2 const 0";
+        let mut fiber = Fiber::new(assemble(src).unwrap()).with_fuel(7);
+        assert!(matches!(fiber.run(), Err(Effect::OutOfFuel)));
+        assert_eq!(fiber.stack_data(), [Data::Int(9), Data::Int(2)]);
+        assert_eq!(fiber.frame_count(), 1);
+    }
+
+    #[test]
+    fn closures_over_different_captures_are_not_the_same() {
+        let body = Rc::new(vec![Op::Get(Name(0)), Op::Return(1)]);
+        let closure = |n| Fun {
+            ops: body.clone(),
+            captures: Rc::new(vec![Data::Int(n)]),
+            spans: None,
+        };
+        assert!(closure(1).same(&closure(1)));
+        assert!(!closure(1).same(&closure(2)));
+        assert_ne!(Data::Fun(closure(1)), Data::Fun(closure(2)));
+        // the same body compiled twice isn't the same function
+        let other = Fun { ops: Rc::new((*body).clone()), ..closure(1) };
+        assert!(!closure(1).same(&other));
+    }
+
+    #[test]
+    fn eq_op_compares_functions_by_identity() {
+        assert_eq!(run("const 1 closure 1 end dup eq").unwrap(), Data::Bool(true));
+        assert_eq!(run("fun end fun end eq").unwrap(), Data::Bool(false));
+    }
+
+    #[test]
+    fn handler_raise_is_caught_further_out() {
+        // `outer` installs a handler for 1, then calls `inner`,
+        // whose handler for 0 raises 1, adding 10 to its argument
+        let fun = assemble("
+            fun const 10 add resume return 1 end handler 1
+            fun
+                fun raise 1 resume return 1 end handler 0
+                const 5 raise 0 return 1
+            end
+            const unit call return 1
+        ").unwrap();
+        assert_eq!(Fiber::new(fun).run().unwrap(), Data::Int(15));
+
+        // the same, but the handler for 0 aborts, dropping its continuation
+        let fun = assemble("
+            fun const 10 add resume return 1 end handler 1
+            fun
+                fun swap drop raise 1 return 1 end handler 0
+                const 5 raise 0 const 100 add return 1
+            end
+            const unit call return 1
+        ").unwrap();
+        assert_eq!(Fiber::new(fun).run().unwrap(), Data::Int(15));
+    }
+
+    #[test]
+    fn deep_handler_does_not_catch_its_own_raise() {
+        let fun = assemble("fun const 1 raise 0 return 1 end handler 0 const 5 raise 0").unwrap();
+        let mut fiber = Fiber::new(fun).with_max_depth(50).with_fuel(10_000);
+        match fiber.run() {
+            Err(Effect::Virtual { name, args, .. }) => {
+                assert_eq!(name, Name(0));
+                assert_eq!(args, [Data::Int(1)]);
+            },
+            other => panic!("expected the raise to escape, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn raise_past_max_depth_overflows() {
+        let fun = assemble("fun return 1 end handler 0 const 5 raise 0").unwrap();
+        let mut fiber = Fiber::new(fun).with_max_depth(1);
+        assert!(matches!(fiber.run(), Err(Effect::StackOverflow)));
+    }
+
+    #[test]
+    fn call_function_with_two_arguments() {
+        let add = match run("fun add return 1 end").unwrap() {
+            Data::Fun(add) => add,
+            other => panic!("expected a fun, got {:?}", other),
+        };
+        let mut fiber = Fiber::new(fun(vec![]));
+        let result = fiber.call_function(add.clone(), vec![Data::Int(2), Data::Int(3)]);
+        assert_eq!(result.unwrap(), Data::Int(5));
+        let result = fiber.call_function(add, vec![Data::Int(4), Data::Int(5)]);
+        assert_eq!(result.unwrap(), Data::Int(9));
+        assert_eq!(fiber.stack_data(), []);
+    }
+
+    #[test]
+    fn call_function_uses_the_fibers_handlers() {
+        let handler = assemble("drop const 10 resume return 1").unwrap();
+        let mut fiber = handling(Op::Handler(Name(0)), handler);
+        let raise = assemble("raise 0 add return 1").unwrap();
+        let result = fiber.call_function(raise, vec![Data::Int(1), Data::Unit]);
+        assert_eq!(result.unwrap(), Data::Int(11));
+    }
+
+    #[test]
+    fn int_overflow_raises() {
+        let (max, min) = (i64::MAX, i64::MIN);
+        for src in [
+            format!("const {} const 1 add", max),
+            format!("const {} const 1 sub", min),
+            format!("const {} const 2 mul", max),
+            format!("const {} const -1 div", min),
+            format!("const {} neg", min),
+        ] {
+            assert!(matches!(run(&src), Err(Effect::IntegerOverflow)), "{}", src);
+        }
+        assert_eq!(run(&format!("const {} neg", max)).unwrap(), Data::Int(-max));
+    }
+
+    #[test]
+    fn bytes_length_and_indexing() {
+        let bytes = r#"const "hé" str_to_bytes"#;
+        assert_eq!(run(&format!("{} bytes_len", bytes)).unwrap(), Data::Int(3));
+        assert_eq!(run(&format!("{} const 0 bytes_get", bytes)).unwrap(), Data::Int(104));
+        assert_eq!(run(&format!("{} const 2 bytes_get", bytes)).unwrap(), Data::Int(0xa9));
+        let result = run(&format!("{} const 3 bytes_get", bytes));
+        assert!(matches!(result, Err(Effect::IndexOutOfBounds { index: 3, len: 3 })));
+    }
+
+    #[test]
+    fn bytes_round_trip_through_a_string() {
+        let result = run(r#"const "héllo" str_to_bytes bytes_to_str"#);
+        assert_eq!(result.unwrap(), Data::Str("héllo".into()));
+    }
+
+    #[test]
+    fn invalid_utf8_bytes_are_not_a_string() {
+        let bytes = Data::Bytes(Rc::from(&[b'o', b'k', 0xff][..]));
+        let mut fiber = Fiber::new(fun(vec![Op::Const(bytes), Op::BytesToStr]));
+        assert!(matches!(fiber.run(), Err(Effect::InvalidUtf8 { valid_up_to: 2 })));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn profile_counts_the_ops_of_a_loop() {
+        let fun = assemble("
+            const 0 set 0
+            top: get 0 const 5 lt jump_if_false done
+                get 0 const 1 add set 0 jump top
+            done: get 0
+        ").unwrap();
+        let mut fiber = Fiber::new(fun).with_profile(true);
+        assert_eq!(fiber.run().unwrap(), Data::Int(5));
+        let report = fiber.profile_report();
+        let count = |name: &str| report.iter().find(|(op, _)| op == name).map(|(_, n)| *n);
+        assert_eq!(count("Add"), Some(5));
+        assert_eq!(count("Lt"), Some(6));
+        assert_eq!(count("Neg"), None);
+        // twice an iteration, and once more to leave the loop and return
+        assert_eq!(count("Get"), Some(12));
+        assert!(report.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn profile_report_is_empty_when_disabled() {
+        let mut fiber = Fiber::new(assemble("const 1 const 2 add").unwrap());
+        fiber.run().unwrap();
+        assert!(fiber.profile_report().is_empty());
+    }
+
+    #[test]
+    fn each_binop_on_floats_and_ints() {
+        let ops = [
+            (BinOp::Add, 9.0, 9),
+            (BinOp::Sub, 5.0, 5),
+            (BinOp::Mul, 14.0, 14),
+            (BinOp::Div, 3.5, 3),
+            (BinOp::Mod, 1.0, 1),
+        ];
+        for (op, float, int) in ops {
+            let result = Data::Float(7.0).apply_binop(Data::Float(2.0), op);
+            assert_eq!(result.unwrap(), Data::Float(float));
+            let result = Data::Int(7).apply_binop(Data::Int(2), op);
+            assert_eq!(result.unwrap(), Data::Int(int));
+        }
+    }
+
+    #[test]
+    fn binop_errors_in_one_place() {
+        for op in [BinOp::Div, BinOp::Mod] {
+            let result = Data::Int(1).apply_binop(Data::Int(0), op);
+            assert!(matches!(result, Err(Effect::ZeroDivision)));
+            let result = Data::Float(1.0).apply_binop(Data::Float(0.0), op);
+            assert!(matches!(result, Err(Effect::ZeroDivision)));
+        }
+        let result = Data::Int(i64::MAX).apply_binop(Data::Int(1), BinOp::Add);
+        assert!(matches!(result, Err(Effect::IntegerOverflow)));
+        let result = Data::Int(1).apply_binop(Data::Float(1.0), BinOp::Add);
+        assert!(matches!(result, Err(Effect::TypeMismatch { expected: "Int", found: "Float" })));
+    }
+
+    #[test]
+    fn reserved_stack_does_not_reallocate() {
+        let src = "const 1 const 2 const 3 const 4 const 5 const 6 pack 6 const 0";
+        let mut fiber = Fiber::with_capacity(assemble(src).unwrap(), 8, 2).with_fuel(7);
+        let (datum, frames) = (fiber.stack.datum.as_ptr(), fiber.stack.frames.as_ptr());
+        assert!(fiber.stack.datum.capacity() >= 8);
+        assert!(matches!(fiber.run(), Err(Effect::OutOfFuel)));
+        assert_eq!(fiber.stack.datum.as_ptr(), datum);
+        assert_eq!(fiber.stack.frames.as_ptr(), frames);
+    }
+
+    #[test]
+    fn captured_continuation_shows_where_it_stopped() {
+        // the handler returns the continuation rather than resuming it
+        let src = "fun drop return 1 end handler 0 const 1 const unit raise 0 const 2";
+        let cont = run(src).unwrap();
+        let fiber = cont.as_cont().unwrap();
+        assert_eq!(fiber.pc(), 5);
+        assert!(matches!(fiber.current_op(), Some(Op::ConstI(2))));
+        assert_eq!(fiber.stack_data(), [Data::Int(1)]);
+        assert!(Data::Int(1).as_cont().is_none());
+    }
+
+    #[test]
+    fn handler_is_installed_into_the_current_frame() {
+        let fun = assemble("const 1 fun end handler 0 const 2").unwrap();
+        let mut fiber = Fiber::new(fun).with_fuel(3);
+        assert!(matches!(fiber.run(), Err(Effect::OutOfFuel)));
+        // the function is taken off the stack, and nothing else
+        assert_eq!(fiber.stack_data(), [Data::Int(1)]);
+        assert!(fiber.stack.frames[0].handlers.contains_key(&Name(0)));
+    }
+
+    #[test]
+    fn handler_goes_with_the_frame_that_installed_it() {
+        let result = run("
+            fun fun drop const 1 resume return 1 end handler 0 const unit return 1 end
+            const unit call drop
+            const unit raise 0
+        ");
+        assert!(matches!(result, Err(Effect::Virtual { name: Name(0), .. })));
+    }
+
+    #[test]
+    fn handler_must_be_a_function() {
+        let result = run("const 1 handler 0");
+        assert!(matches!(result, Err(Effect::TypeMismatch { expected: "Fun", found: "Int" })));
+    }
+
+    #[test]
+    fn rotating_the_top_three_of_four() {
+        let ops = vec![float(1.0), float(2.0), float(3.0), float(4.0), Op::Rot(3)];
+        assert_eq!(stack_after(ops).unwrap(), [1.0, 4.0, 2.0, 3.0]);
+        let ops = vec![float(1.0), float(2.0), Op::Rot(2)];
+        assert_eq!(stack_after(ops).unwrap(), [2.0, 1.0]);
+    }
+
+    #[test]
+    fn rotating_more_than_the_stack_holds_is_fatal() {
+        let mut fiber = Fiber::new(assemble("const 1 const 2 rot 3").unwrap());
+        assert!(matches!(fiber.run(), Err(Effect::Fatal { pc: 2, .. })));
+        assert_eq!(fiber.stack_data(), [Data::Int(1), Data::Int(2)]);
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn nested_values_round_trip_through_json() {
+        let json = serde_json::json!({
+            "list": [1, 2.5, [true, null]],
+            "map": { "name": "effective", "empty": {} },
+        });
+        let data = Data::from_json(&json).unwrap();
+        assert_eq!(data.to_json().unwrap(), json);
+
+        match &data {
+            Data::Map(map) => {
+                let list = &map.borrow()[&Key::Str("list".into())];
+                assert_eq!(format!("{}", list), "[1, 2.5, [true, ()]]");
+            },
+            other => panic!("expected a map, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn functions_have_no_json() {
+        assert!(matches!(Data::Fun(fun(vec![])).to_json(), Err(Effect::TypeMismatch { .. })));
+        assert!(matches!(Data::Float(f64::NAN).to_json(), Err(Effect::TypeMismatch { .. })));
+        let map = Data::Map(Rc::new(RefCell::new(
+            vec![(Key::Int(1), Data::Unit)].into_iter().collect()
+        )));
+        assert!(map.to_json().is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn list_inside_itself_has_no_json() {
+        assert!(matches!(
+            Data::List(list_holding_itself()).to_json(),
+            Err(Effect::TypeMismatch { found: "cyclic List", .. }),
+        ));
+
+        // a list in two places that isn't inside itself converts in full
+        let inner = Data::List(Rc::new(RefCell::new(vec![Data::Int(1)])));
+        let outer = Data::List(Rc::new(RefCell::new(vec![inner.clone(), inner])));
+        assert_eq!(outer.to_json().unwrap(), serde_json::json!([[1], [1]]));
+    }
+
+    #[test]
+    fn return_discards_scratch_values_but_keeps_the_result() {
+        let src = "const 7 fun drop const 1 const 2 const 3 return 1 end const unit call const 0";
+        let mut fiber = Fiber::new(assemble(src).unwrap()).with_fuel(9);
+        assert!(matches!(fiber.run(), Err(Effect::OutOfFuel)));
+        assert_eq!(fiber.stack_data(), [Data::Int(7), Data::Int(3)]);
+    }
+
+    #[test]
+    fn return_cannot_reach_below_its_frame() {
+        let result = run("const 7 fun drop return 2 end const unit call");
+        assert!(matches!(result, Err(Effect::Fatal { .. })));
+    }
+
+    #[test]
+    #[cfg(not(feature = "std"))]
+    fn runs_and_prints_without_std() {
+        // `cargo test --no-default-features` builds the crate as `no_std`
+        let (buffer, sink) = buffer();
+        let fun = assemble(r#"const "hi" print const 1 const 2 add"#).unwrap();
+        let mut fiber = Fiber::new(fun).with_output(sink);
+        assert_eq!(fiber.run().unwrap(), Data::Int(3));
+        assert_eq!(contents(&buffer), "hi\n");
+        assert_eq!(format!("{}", Effect::ZeroDivision), "division by zero");
+    }
+
+    #[test]
+    fn nan_and_infinity_predicates() {
+        let cases = [
+            (f64::NAN, true, false),
+            (f64::INFINITY, false, true),
+            (-f64::INFINITY, false, true),
+            (1.5, false, false),
+        ];
+        for (n, nan, inf) in cases {
+            let mut fiber = Fiber::new(fun(vec![float(n), Op::IsNaN]));
+            assert_eq!(fiber.run().unwrap(), Data::Bool(nan));
+            let mut fiber = Fiber::new(fun(vec![float(n), Op::IsInf]));
+            assert_eq!(fiber.run().unwrap(), Data::Bool(inf));
+        }
+        assert!(matches!(run("const 1 is_nan"), Err(Effect::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn nan_and_infinity_propagate_through_arithmetic() {
+        let ops = vec![float(f64::INFINITY), float(0.0), Op::Mul, Op::IsNaN];
+        assert_eq!(Fiber::new(fun(ops)).run().unwrap(), Data::Bool(true));
+        let ops = vec![float(f64::MAX), float(2.0), Op::Mul, Op::IsInf];
+        assert_eq!(Fiber::new(fun(ops)).run().unwrap(), Data::Bool(true));
+        // only dividing by zero raises
+        assert!(matches!(run("const 0.0 const 0.0 div"), Err(Effect::ZeroDivision)));
+    }
+
+    #[test]
+    fn fatal_error_two_calls_deep_has_a_backtrace() {
+        let fun = assemble("
+            fun
+                fun drop add return 1 end
+                const unit call return 1
+            end
+            const unit call
+        ").unwrap();
+        let mut fiber = Fiber::new(fun);
+        match fiber.run() {
+            Err(Effect::Fatal { pc, backtrace, .. }) => {
+                assert_eq!(pc, 1);
+                let pcs: Vec<usize> = backtrace.iter().map(|entry| entry.pc).collect();
+                // innermost first, then where each caller carries on
+                assert_eq!(pcs, [1, 3, 3]);
+            },
+            other => panic!("expected a fatal error, got {:?}", other),
+        }
+        assert_eq!(fiber.backtrace().len(), 3);
+    }
+
+    #[test]
+    fn resumes_in_a_loop_are_bounded_by_max_depth() {
+        // each raise resumes from a new handler fiber, which waits
+        // on the continuation with the ones before it
+        let fun = assemble("
+            fun resume return 1 end handler 0
+            const 0 set 0
+            top: get 0 const 400000 lt jump_if_false done
+            get 0 raise 0 const 1 add set 0 jump top
+            done: get 0
+        ").unwrap();
+        let mut fiber = Fiber::new(fun).with_max_depth(100).with_fuel(10_000_000);
+        assert!(matches!(fiber.run(), Err(Effect::StackOverflow)));
+        drop(fiber);
+    }
+
+    #[test]
+    fn long_chains_of_fibers_drop_without_overflowing() {
+        let mut fiber = Fiber::new(fun(vec![]));
+        for _ in 0..200_000 {
+            let mut next = Fiber::new(fun(vec![]));
+            next.resumer = Some(Box::new(fiber));
+            fiber = next;
+        }
+        drop(fiber);
+
+        let mut fiber = Fiber::new(fun(vec![]));
+        for _ in 0..200_000 {
+            let mut next = Fiber::new(fun(vec![]));
+            next.stack.datum.push(Data::Cont(Rc::new(fiber)));
+            fiber = next;
+        }
+        drop(fiber);
+    }
+
+    #[test]
+    fn state_handler_observes_the_final_value() {
+        // `get` resumes with the state, 5, and adds 100 to what comes back
+        let fun = assemble("
+            fun drop const 5 resume const 100 add return 1 end handler get
+            const unit raise get const 2 mul
+        ").unwrap();
+        assert_eq!(Fiber::new(fun).run().unwrap(), Data::Int(110));
+    }
+
+    #[test]
+    fn each_handler_observes_the_value_of_the_rest() {
+        // the second `get` finishes first, so its handler sees 10,
+        // and the first handler sees what the second returned
+        let fun = assemble("
+            fun drop const 5 resume const 100 add return 1 end handler get
+            const unit raise get const unit raise get add
+        ").unwrap();
+        assert_eq!(Fiber::new(fun).run().unwrap(), Data::Int(210));
+    }
+
+    #[test]
+    fn truthiness_of_each_type() {
+        let cases = [
+            ("const unit", false),
+            ("const false", false),
+            ("const true", true),
+            ("const 0.0", false),
+            ("const -0.0", false),
+            ("const 0.5", true),
+            ("const 0", false),
+            ("const -1", true),
+            (r#"const """#, false),
+            (r#"const "a""#, true),
+            ("const 'a'", true),
+            (r#"const "" str_to_bytes"#, false),
+            (r#"const "a" str_to_bytes"#, true),
+            ("pack 0", false),
+            ("const 0 pack 1", true),
+            ("list_new", false),
+            ("list_new const 0 list_push", true),
+            ("map_new", false),
+            ("map_new const 0 const 0 map_set", true),
+            ("fun end", true),
+        ];
+        for (src, truthy) in cases {
+            assert_eq!(run(&format!("{} to_bool", src)).unwrap(), Data::Bool(truthy), "{}", src);
+        }
+    }
+
+    #[test]
+    fn jump_if_false_after_to_bool() {
+        let src = "const 0 to_bool jump_if_false zero const 1 jump done zero: const 2 done:";
+        assert_eq!(run(src).unwrap(), Data::Int(2));
+    }
+
+    #[test]
+    fn program_runs_end_to_end() {
+        let mut program = Program::new(fun(vec![Op::ConstIdx(0), Op::ConstIdx(1), Op::Concat]));
+        program.constants = vec![Data::Str("effect".into()), Data::Str("ive".into())];
+        assert_eq!(program.run().unwrap(), Data::Str("effective".into()));
+
+        let mut program = Program::new(fun(vec![int(1), Op::Raise(Name(0))]));
+        program.symbols.intern("log");
+        match program.run() {
+            Err(Effect::Virtual { symbol, .. }) => assert_eq!(symbol.as_deref(), Some("log")),
+            other => panic!("expected the raise to escape, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn fuel_pauses_between_ops_around_handlers() {
+        let src = "
+            fun const 10 add resume return 1 end handler 0
+            const 1 const 2 raise 0 add const 3 mul
+        ";
+        let expected = run(src).unwrap();
+        assert_eq!(expected, Data::Int(39));
+
+        // one op at a time, topping up whenever it runs out
+        let mut fiber = Fiber::new(assemble(src).unwrap()).with_fuel(1);
+        let mut pauses = 0;
+        let result = loop {
+            match fiber.run() {
+                Err(Effect::OutOfFuel) => { pauses += 1; fiber.add_fuel(1); },
+                other => break other,
+            }
+        };
+        assert_eq!(result.unwrap(), expected);
+        assert!(pauses > 10);
+    }
+
+    #[test]
+    fn scalar_literals_are_inline() {
+        assert!(matches!(Op::constant(Data::Float(1.5)), Op::ConstF(n) if n == 1.5));
+        assert!(matches!(Op::constant(Data::Int(3)), Op::ConstI(3)));
+        assert!(matches!(Op::constant(Data::Bool(true)), Op::Const(Data::Bool(true))));
+        let fun = assemble("const 1.5 const 3").unwrap();
+        assert!(matches!(fun.ops[..], [Op::ConstF(_), Op::ConstI(3)]));
+        // the scalars fit in the room `Const` takes, so `Op` is no bigger
+        assert!(core::mem::size_of::<Op>() <= core::mem::size_of::<(usize, Data)>());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn scalar_constants_encode_smaller() {
+        let size = |op| Program::new(fun(vec![op])).to_bytes().unwrap().len();
+        assert!(size(Op::ConstF(1.5)) < size(Op::Const(Data::Float(1.5))));
+        assert!(size(Op::ConstI(3)) < size(Op::Const(Data::Int(3))));
+    }
+
+    #[test]
+    fn scalar_constants_push_their_value() {
+        let ops = vec![Op::ConstF(1.5), Op::ConstI(3), Op::Const(Data::Unit)];
+        let mut fiber = Fiber::new(fun(ops)).with_fuel(2);
+        assert!(matches!(fiber.run(), Err(Effect::OutOfFuel)));
+        assert_eq!(fiber.stack_data(), [Data::Float(1.5), Data::Int(3)]);
+    }
+
+    #[test]
+    fn unhandle_ends_the_handlers_scope() {
+        let src = "fun drop const 1 resume return 1 end handler 0 const unit raise 0 unhandle 0";
+        assert!(matches!(run(src), Ok(Data::Int(1))));
+        let result = run(&format!("{} const unit raise 0", src));
+        assert!(matches!(result, Err(Effect::Virtual { name: Name(0), .. })));
+    }
+
+    #[test]
+    fn unhandling_a_missing_handler_does_nothing() {
+        assert_eq!(run("const 1 unhandle 0").unwrap(), Data::Int(1));
+    }
+
+    #[test]
+    fn unhandle_leaves_outer_frames_alone() {
+        let result = run("
+            fun drop const 1 resume return 1 end handler 0
+            fun unhandle 0 const unit raise 0 return 1 end
+            const unit call
+        ");
+        assert_eq!(result.unwrap(), Data::Int(1));
+    }
+
+    #[test]
+    fn try_raise_with_a_handler_raises() {
+        let src = "fun const 10 add resume return 1 end handler 0 const 2 try_raise 0";
+        assert_eq!(run(src).unwrap(), Data::Int(12));
+        let mut fiber = Fiber::new(assemble("const 2 try_raise 0").unwrap())
+            .with_host(Name(0), |_| Ok(Data::Int(7)));
+        assert_eq!(fiber.run().unwrap(), Data::Int(7));
+    }
+
+    #[test]
+    fn try_raise_without_a_handler_leaves_its_argument() {
+        assert_eq!(run("const 2 try_raise 0").unwrap(), Data::Int(2));
+        assert_eq!(run("const 2 try_raise 0 const 3 add").unwrap(), Data::Int(5));
+    }
+
+    #[test]
+    fn pushing_past_max_stack_overflows_without_pushing() {
+        let mut fiber = Fiber::new(assemble("top: const 1 jump top").unwrap())
+            .with_max_stack(100);
+        assert!(matches!(fiber.run(), Err(Effect::DatumOverflow)));
+        assert_eq!(fiber.stack_data().len(), 100);
+        assert_eq!(fiber.pc(), 0);
+
+        // the op that overflowed is still next, so it overflows again
+        assert!(matches!(fiber.run(), Err(Effect::DatumOverflow)));
+        assert_eq!(fiber.stack_data().len(), 100);
+    }
+
+    #[test]
+    fn unpack_past_max_stack_overflows() {
+        let tuple = Data::Tuple(Rc::new(vec![Data::Int(1), Data::Int(2), Data::Int(3)]));
+        let mut fiber = Fiber::new(fun(vec![Op::Const(tuple), Op::Unpack]))
+            .with_max_stack(2);
+        assert!(matches!(fiber.run(), Err(Effect::DatumOverflow)));
+        assert_eq!(fiber.current_op().map(|op| matches!(op, Op::Unpack)), Some(true));
+    }
+
+    #[test]
+    fn over_and_nip_on_three_values() {
+        let three = |op| vec![float(1.0), float(2.0), float(3.0), op];
+        assert_eq!(stack_after(three(Op::Over)).unwrap(), [1.0, 2.0, 3.0, 2.0]);
+        assert_eq!(stack_after(three(Op::Nip)).unwrap(), [1.0, 3.0]);
+        let two = vec![float(1.0), float(2.0), Op::Over];
+        assert_eq!(stack_after(two).unwrap(), [1.0, 2.0, 1.0]);
+    }
+
+    #[test]
+    fn over_and_nip_need_two_values() {
+        for src in ["const 1 over", "over", "const 1 nip", "nip"] {
+            assert!(matches!(run(src), Err(Effect::Fatal { .. })), "{}", src);
+        }
+    }
+
+    #[test]
+    fn raising_with_the_wrong_payload_is_rejected_at_the_raise() {
+        let src = "fun drop const 1 resume return 1 end handler 0 const unit raise 0";
+        let int = || Signature { args: vec!["Int"], resume: None };
+        let mut fiber = Fiber::new(assemble(src).unwrap()).with_signature(Name(0), int());
+        assert!(matches!(
+            fiber.run(),
+            Err(Effect::EffectTypeMismatch { name: Name(0), expected: "Int", found: "Unit" }),
+        ));
+        // the handler never ran
+        assert_eq!(fiber.pc(), 3);
+
+        let src = "fun drop const 1 resume return 1 end handler 0 const 2 raise 0";
+        let mut fiber = Fiber::new(assemble(src).unwrap()).with_signature(Name(0), int());
+        assert_eq!(fiber.run().unwrap(), Data::Int(1));
+    }
+
+    #[test]
+    fn resuming_with_the_wrong_type_is_rejected() {
+        let src = "fun drop const unit resume return 1 end handler 0 const 2 raise 0";
+        let signature = Signature { args: vec!["Int"], resume: Some("Int") };
+        let mut fiber = Fiber::new(assemble(src).unwrap()).with_signature(Name(0), signature);
+        assert!(matches!(
+            fiber.run(),
+            Err(Effect::EffectTypeMismatch { name: Name(0), expected: "Int", found: "Unit" }),
+        ));
+    }
+
+    #[test]
+    fn raising_with_the_wrong_number_of_arguments_is_rejected() {
+        let signature = Signature { args: vec!["Int"], resume: None };
+        let mut fiber = Fiber::new(assemble("const 1 const 2 raise_n 0 2").unwrap())
+            .with_signature(Name(0), signature);
+        assert!(matches!(
+            fiber.run(),
+            Err(Effect::EffectArityMismatch { name: Name(0), expected: 1, found: 2 }),
+        ));
+    }
+
+    #[test]
+    fn try_raise_without_a_handler_still_checks_its_signature() {
+        let signature = Signature { args: vec!["Int"], resume: None };
+        let mut fiber = Fiber::new(assemble("const unit try_raise 0").unwrap())
+            .with_signature(Name(0), signature);
+        assert!(matches!(
+            fiber.run(),
+            Err(Effect::EffectTypeMismatch { name: Name(0), expected: "Int", found: "Unit" }),
+        ));
+    }
+
+    #[test]
+    fn host_captures_are_read_with_get() {
+        let double = Data::Native(Native::new(|data| match data {
+            Data::Int(n) => Ok(Data::Int(n * 2)),
+            other => Err(Effect::mismatch("Int", &other)),
+        }));
+        let program = Program::new(assemble("get 0 const 21 call get 1 add").unwrap());
+        let mut fiber = program.fiber_with_captures(Rc::new(vec![double, Data::Int(100)]));
+        assert_eq!(fiber.run().unwrap(), Data::Int(142));
+
+        // and are still there after a reset
+        fiber.reset();
+        assert_eq!(fiber.run().unwrap(), Data::Int(142));
+    }
+
+    #[test]
+    fn program_without_host_captures_has_none() {
+        let program = Program::new(assemble("get 0").unwrap());
+        assert!(matches!(program.run(), Err(Effect::Fatal { .. })));
+    }
+
+    #[test]
+    fn pretty_printing_the_demo() {
+        let expected = "\
+fun captures 0 ops 8
+    0000  Const     Float(3.0)
+    0001  Const     Float(4.0)
+    0002  Const     Float(5.0)
+    0003  Add
+    0004  Div
+    0005  Const     <fun> captures 0 ops 1
+        0000  Call
+    0006  Handler   Name(0)
+    0007  Raise     Name(0)
+";
+        assert_eq!(demo().pretty(), expected);
+    }
+
+    #[test]
+    fn pretty_printing_functions_two_deep() {
+        let inner = Data::Fun(fun(vec![Op::ConstI(1)]));
+        let outer = Data::Fun(fun(vec![Op::Const(inner), Op::Call]));
+        let text = fun(vec![Op::Const(outer)]).pretty();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines, [
+            "fun captures 0 ops 1",
+            "    0000  Const     <fun> captures 0 ops 2",
+            "        0000  Const     <fun> captures 0 ops 1",
+            "            0000  ConstI    1",
+            "        0001  Call",
+        ]);
+    }
+
+    #[test]
+    fn repeating_a_string() {
+        assert_eq!(run("const \"ab\" const 3 str_repeat").unwrap(), Data::Str("ababab".into()));
+        assert_eq!(run("const \"ab\" const 0 str_repeat").unwrap(), Data::Str("".into()));
+        assert!(matches!(
+            run("const \"ab\" const -1 str_repeat"),
+            Err(Effect::NegativeCount(-1)),
+        ));
+        assert!(matches!(
+            run("const 3 const \"ab\" str_repeat"),
+            Err(Effect::TypeMismatch { .. }),
+        ));
+    }
+
+    #[test]
+    fn repeating_past_the_max_length_fails() {
+        let src = "const \"ab\" const 5 str_repeat";
+        let mut fiber = Fiber::new(assemble(src).unwrap()).with_max_str_len(8);
+        assert!(matches!(fiber.run(), Err(Effect::StrTooLong { len: 10, max: 8 })));
+
+        let mut fiber = Fiber::new(assemble(src).unwrap()).with_max_str_len(10);
+        assert_eq!(fiber.run().unwrap(), Data::Str("ababababab".into()));
+
+        // a count too big to multiply out doesn't overflow
+        let src = format!("const \"ab\" const {} str_repeat", i64::MAX);
+        assert!(matches!(run(&src), Err(Effect::StrTooLong { .. })));
+    }
+
+    #[test]
+    fn halt_skips_the_ops_after_it() {
+        assert_eq!(run("const 1 halt const 2 add").unwrap(), Data::Int(1));
+        let mut fiber = Fiber::new(assemble("const 1 halt const 2 add").unwrap());
+        assert_eq!(fiber.step().unwrap(), Status::Running);
+        assert_eq!(fiber.step().unwrap(), Status::Done(Data::Int(1)));
+    }
+
+    #[test]
+    fn halt_leaves_nested_calls_without_returning() {
+        let src = "
+            fun drop fun drop const 5 halt return 1 end const unit call return 1 end
+            const unit call const 100 add
+        ";
+        assert_eq!(run(src).unwrap(), Data::Int(5));
+    }
+
+    #[test]
+    fn halt_needs_a_result() {
+        assert!(matches!(run("halt"), Err(Effect::Fatal { .. })));
+    }
+
+    #[test]
+    fn passing_assertion_carries_on() {
+        let src = "const 2.0 const 3.0 add const 5.0 eq assert const 1";
+        assert_eq!(run(src).unwrap(), Data::Int(1));
+    }
+
+    #[test]
+    fn failing_assertion_raises() {
+        let src = "const 2.0 const 3.0 add const 6.0 eq assert const 1";
+        assert!(matches!(run(src), Err(Effect::AssertionFailed { pc: 5, message: None })));
+
+        let src = "const 2.0 const 3.0 add const 6.0 eq const \"sum\" assert_msg const 1";
+        match run(src) {
+            Err(Effect::AssertionFailed { pc: 6, message: Some(message) }) => {
+                assert_eq!(&*message, "sum");
+            },
+            other => panic!("expected a failed assertion, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn asserting_a_non_bool_is_a_type_mismatch() {
+        assert!(matches!(
+            run("const 1 assert"),
+            Err(Effect::TypeMismatch { expected: "Bool", found: "Int" }),
+        ));
+        assert!(matches!(
+            run("const true const 1 assert_msg"),
+            Err(Effect::TypeMismatch { expected: "Str", found: "Int" }),
+        ));
+    }
+
+    #[test]
+    fn yield_pauses_step_until_stepped_again() {
+        let mut fiber = Fiber::new(assemble("const 1 yield const 2 add").unwrap());
+        assert_eq!(fiber.step().unwrap(), Status::Running);
+        assert_eq!(fiber.step().unwrap(), Status::Yielded);
+        assert_eq!(fiber.stack_data(), [Data::Int(1)]);
+
+        // the host can do its own work here, then carry on
+        assert_eq!(fiber.step().unwrap(), Status::Running);
+        assert_eq!(fiber.step().unwrap(), Status::Done(Data::Int(3)));
+    }
+
+    #[test]
+    fn run_stops_at_a_yield_until_resumed() {
+        let src = "const 1 yield const 2 add yield const 3 mul";
+        let mut fiber = Fiber::new(assemble(src).unwrap());
+        assert!(matches!(fiber.run(), Err(Effect::Yielded)));
+
+        // the host gets control back at each yield, then carries on
+        let mut seen = vec![fiber.stack_data().to_vec()];
+        let result = loop {
+            match fiber.resume() {
+                Err(Effect::Yielded) => seen.push(fiber.stack_data().to_vec()),
+                other => break other,
+            }
+        };
+        assert_eq!(result.unwrap(), Data::Int(9));
+        assert_eq!(seen, [[Data::Int(1)], [Data::Int(3)]]);
+    }
+
+    #[test]
+    fn run_suspending_hands_back_the_fiber_at_a_yield() {
+        let fiber = Fiber::new(assemble("const 1 yield const 2 add").unwrap());
+        let fiber = match fiber.run_suspending().unwrap() {
+            Outcome::Yielded(fiber) => fiber,
+            other => panic!("expected a yield, got {:?}", other),
+        };
+        match fiber.run_suspending().unwrap() {
+            Outcome::Done(data) => assert_eq!(data, Data::Int(3)),
+            other => panic!("expected it to finish, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn events_carry_on_past_a_yield() {
+        let mut fiber = Fiber::new(assemble("yield const 1").unwrap());
+        let events: Vec<Event> = fiber.events().collect::<Result<_, _>>().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].result, Some(Data::Int(1)));
+    }
+
+    /// Steps `fiber` up to its first `Yield`.
+    #[cfg(debug_assertions)]
+    fn step_to_yield(fiber: &mut Fiber) {
+        while fiber.step().unwrap() != Status::Yielded {}
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn rc_report_counts_captured_continuations() {
+        // each handler keeps its continuation, the second holding the first
+        let src = "
+            fun
+                drop fun drop yield return 1 end handler 1
+                const unit raise 1 return 1
+            end handler 0
+            const unit raise 0
+        ";
+        let mut fiber = Fiber::new(assemble(src).unwrap());
+        step_to_yield(&mut fiber);
+        let report = fiber.live_rc_report();
+        assert_eq!(report["Cont"].values, 2);
+        assert!(report["Cont"].strong >= 2);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn rc_report_counts_shared_values_once() {
+        let tuple = Data::Tuple(Rc::new(vec![Data::Str("a".into())]));
+        let mut fiber = Fiber::new(fun(vec![
+            Op::Const(tuple.clone()),
+            Op::Const(tuple),
+            Op::Yield,
+        ]));
+        step_to_yield(&mut fiber);
+        let report = fiber.live_rc_report();
+        assert_eq!(report["Tuple"].values, 1);
+        assert_eq!(report["Str"].values, 1);
+        assert!(!report.contains_key("Cont"));
     }
 }